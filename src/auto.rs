@@ -0,0 +1,178 @@
+//! Automatic diagnosis of failed commands in a WTG session.
+//!
+//! With `wtg s --auto`, each command which exits with a non-zero status (as reported by the shell
+//! integration) is sent to the model, and a short diagnosis is streamed below its output, before
+//! the next prompt. The diagnosis is only shown on the terminal, it is not recorded.
+//!
+//! While a diagnosis streams, the terminal waits for it, at most [`DIAGNOSIS_TIMEOUT`] (e.g. if
+//! the endpoint hangs). Diagnoses are rate limited to one every `--auto-interval` seconds. Failures which are rarely
+//! errors are skipped: programs which report a result with their exit status (e.g. `grep` without
+//! a match, `test`, `diff`) and commands interrupted with Ctrl-C or suspended with Ctrl-Z.
+
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::budget::{fit_contexts, output_budget};
+use crate::cli::{LlmArgs, SessionArgs};
+use crate::config::Config;
+use crate::errors::WtgError;
+use crate::history::{CommandIndex, CommandMeta, CommandRecord, LogRecord};
+use crate::llm::{build_provider, query_llm, LlmSettings};
+use crate::recorder::program_name;
+use crate::redact::Redactor;
+
+/// Programs whose non-zero exit status is usually a result rather than an error
+const IGNORED_PROGRAMS: &[&str] = &[
+    "grep", "egrep", "fgrep", "rg", "test", "[", "[[", "diff", "cmp", "false", "which", "wtg",
+];
+/// Exit statuses of commands interrupted (Ctrl-C), stopped by a closed pipe (e.g. `| head`) or
+/// suspended (Ctrl-Z)
+const IGNORED_EXIT_CODES: &[i32] = &[130, 141, 148];
+/// Default seconds between diagnoses
+const DEFAULT_INTERVAL: u64 = 30;
+/// The longest the terminal waits for a diagnosis
+pub const DIAGNOSIS_TIMEOUT: Duration = Duration::from_secs(10);
+const AUTO_PROMPT: &str = "The command failed. In at most three short sentences, say why it \
+failed and how to fix it. Reply in plain text without markdown.";
+
+/// Diagnoses failed commands with the model
+pub struct AutoDiagnosis {
+    settings: LlmSettings,
+    redactor: Redactor,
+    /// Programs whose failures are not diagnosed
+    ignore: Vec<String>,
+    /// The least time between diagnoses
+    interval: Duration,
+    /// When the last diagnosis started
+    last: Option<Instant>,
+}
+
+impl AutoDiagnosis {
+    /// Set up diagnoses if enabled with `--auto` or `auto` in the `[session]` table of the config
+    /// file. The options are resolved from the CLI args, then the config file. If the provider
+    /// cannot be set up (e.g. no API key), `--auto` is an error, while `auto` in the config only
+    /// prints a warning and the session starts without diagnoses.
+    pub fn resolve(args: &SessionArgs, llm: &LlmArgs) -> Result<Option<Self>, WtgError> {
+        let config = Config::load()?.session;
+        // exit statuses are only known with the shell integration
+        if !(args.auto || config.auto.unwrap_or(false)) || args.no_shell_integration {
+            return Ok(None);
+        }
+        // the provider is built again for each diagnosis, on its own thread
+        let resolved = LlmSettings::resolve(llm)
+            .and_then(|settings| build_provider(&settings).map(|_| settings));
+        let settings = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) if !args.auto => {
+                eprintln!(
+                    "Not diagnosing failed commands (`auto` in the config): {}",
+                    e
+                );
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let ignore = IGNORED_PROGRAMS
+            .iter()
+            .map(|program| program.to_string())
+            .chain(config.auto_ignore)
+            .chain(args.auto_ignore.iter().cloned())
+            .collect();
+        let interval = args
+            .auto_interval
+            .or(config.auto_interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        Ok(Some(AutoDiagnosis {
+            settings,
+            redactor: Redactor::load()?,
+            ignore,
+            interval: Duration::from_secs(interval),
+            last: None,
+        }))
+    }
+
+    /// Whether a failed command should be diagnosed now
+    fn should_diagnose(&self, meta: &CommandMeta) -> bool {
+        if meta
+            .exit_code
+            .is_none_or(|code| code == 0 || IGNORED_EXIT_CODES.contains(&code))
+        {
+            return false;
+        }
+        let ignored = meta
+            .command
+            .as_deref()
+            .and_then(program_name)
+            .is_some_and(|program| self.ignore.iter().any(|ignored| ignored == program));
+        !ignored && self.last.is_none_or(|last| last.elapsed() >= self.interval)
+    }
+
+    /// Stream a diagnosis of a failed command to `out`, unless the command is ignored or a
+    /// diagnosis was shown recently. `out` is the terminal in raw mode, so newlines are written
+    /// as `\r\n`. Errors from the model are shown in place of the diagnosis. The model is queried
+    /// on another thread, which is abandoned after [`DIAGNOSIS_TIMEOUT`].
+    pub fn diagnose(&mut self, record: LogRecord, out: &mut impl Write) -> io::Result<()> {
+        if !self.should_diagnose(&record.meta) {
+            return Ok(());
+        }
+        self.last = Some(Instant::now());
+        let mut contexts =
+            vec![CommandRecord::from_log_record(CommandIndex::FromEnd(1), record).to_context()];
+        self.redactor.redact_contexts(&mut contexts);
+        if let Some(context_window) = self.settings.model_info.context_window {
+            fit_contexts(
+                &mut contexts,
+                output_budget(context_window, AUTO_PROMPT),
+                None,
+            );
+        }
+        let (sender, receiver) = mpsc::channel();
+        let settings = self.settings.clone();
+        std::thread::spawn(move || {
+            let result = build_provider(&settings)
+                .map_err(|e| e.to_string())
+                .and_then(|provider| {
+                    query_llm(
+                        provider.as_ref(),
+                        &contexts,
+                        AUTO_PROMPT,
+                        &settings.model,
+                        &mut |delta| {
+                            // fails once the diagnosis was abandoned, which ends the request
+                            sender
+                                .send(Ok(delta.to_string()))
+                                .map_err(|_| io::Error::other("diagnosis abandoned"))
+                        },
+                    )
+                    .map_err(|e| format!("Error querying {}: {}", provider.name(), e))
+                });
+            if let Err(e) = result {
+                let _ = sender.send(Err(e));
+            }
+        });
+        write!(out, "\r\n[wtg] ")?;
+        out.flush()?;
+        let deadline = Instant::now() + DIAGNOSIS_TIMEOUT;
+        loop {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Ok(delta)) => {
+                    out.write_all(delta.replace('\n', "\r\n").as_bytes())?;
+                    out.flush()?;
+                }
+                Ok(Err(e)) => {
+                    write!(out, "{}", e)?;
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    write!(out, " [timed out, run `wtg q` to ask again]")?;
+                    break;
+                }
+                // the response is complete
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        write!(out, "\r\n")?;
+        out.flush()
+    }
+}
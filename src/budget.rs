@@ -0,0 +1,363 @@
+//! Fit command output into the model's context window.
+//!
+//! Token counts are estimated at roughly four characters per token, which is close for English
+//! text and code with OpenAI style tokenizers. The context window, less room for the prompt and
+//! the response, is shared between the selected commands. Outputs over their share are
+//! truncated by [`truncate_output`] to the start and end of the output plus the snippets around
+//! errors (see [`crate::diagnostics`]). With `--summarize`, they are first condensed by the model
+//! with [`summarize_output`].
+
+use std::error::Error;
+
+use crate::cli::Model;
+use crate::diagnostics::find_snippets;
+use crate::llm::{ChatMessage, CommandContext, Provider};
+
+/// Estimated characters per token
+const CHARS_PER_TOKEN: usize = 4;
+/// Tokens reserved for the model's response, at most a quarter of the context window
+const RESPONSE_RESERVE: usize = 4096;
+/// Tokens reserved for the system message text and the command headers
+const MESSAGE_OVERHEAD: usize = 256;
+/// Lines longer than this are shortened when output is truncated
+const MAX_LINE_CHARS: usize = 1000;
+/// The most output chunks summarized separately, longer output is truncated first
+const MAX_SUMMARY_CHUNKS: usize = 16;
+/// Summaries of summaries are made until the output fits, at most this many times
+const MAX_SUMMARY_ROUNDS: usize = 2;
+const SUMMARY_PROMPT: &str = "You summarize part of the output of a terminal command for \
+another assistant which will explain the output to the user. Keep every error, warning, failed \
+test, file path, line number and exit status verbatim. Briefly summarize everything else. \
+Reply with the summary only.";
+
+/// Estimate the number of tokens in `text`
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// The tokens available for command output, after reserving room for the prompt and response
+pub fn output_budget(context_window: usize, prompt: &str) -> usize {
+    let reserve = RESPONSE_RESERVE.min(context_window / 4);
+    context_window.saturating_sub(reserve + MESSAGE_OVERHEAD + estimate_tokens(prompt))
+}
+
+/// Split `budget` between outputs of the given sizes. Outputs smaller than an even share keep
+/// their full size and the larger outputs share the rest.
+fn allot(sizes: &[usize], budget: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| sizes[i]);
+    let mut allotted = vec![0; sizes.len()];
+    let mut remaining = budget;
+    for (k, &i) in order.iter().enumerate() {
+        allotted[i] = sizes[i].min(remaining / (sizes.len() - k));
+        remaining -= allotted[i];
+    }
+    allotted
+}
+
+/// Shorten the outputs of `contexts` to fit in `budget` tokens in total. Outputs over their
+/// share are summarized with the `summarizer` provider and model (if given), then truncated
+/// if still too long. Returns the number of outputs which were shortened.
+pub fn fit_contexts(
+    contexts: &mut [CommandContext],
+    budget: usize,
+    summarizer: Option<(&dyn Provider, &Model)>,
+) -> usize {
+    let sizes: Vec<usize> = contexts
+        .iter()
+        .map(|context| estimate_tokens(&context.output))
+        .collect();
+    let allotted = allot(&sizes, budget);
+    let mut shortened = 0;
+    for ((context, size), max_tokens) in contexts.iter_mut().zip(sizes).zip(allotted) {
+        if size <= max_tokens {
+            continue;
+        }
+        shortened += 1;
+        if let Some((provider, model)) = summarizer {
+            match summarize_output(provider, model, context, budget, max_tokens) {
+                Ok(summary) => context.output = summary,
+                Err(e) => eprintln!("Error summarizing output, truncating it instead: {}", e),
+            }
+        }
+        if estimate_tokens(&context.output) > max_tokens {
+            context.output = truncate_output(&context.output, max_tokens);
+        }
+    }
+    shortened
+}
+
+fn shorten_line(line: &str) -> String {
+    if line.chars().count() <= MAX_LINE_CHARS {
+        line.to_string()
+    } else {
+        let mut shortened: String = line.chars().take(MAX_LINE_CHARS).collect();
+        shortened.push('…');
+        shortened
+    }
+}
+
+/// Lines chosen to be kept from the output, within a character budget which also covers the
+/// markers in place of the omitted lines
+struct Selection<'a> {
+    lines: &'a [String],
+    kept: Vec<bool>,
+    /// Characters of the kept lines and the markers
+    chars: usize,
+    /// Characters of each marker, sized for the largest line count
+    marker_chars: usize,
+}
+
+impl Selection<'_> {
+    fn new(lines: &[String]) -> Selection<'_> {
+        let marker_chars = omitted_marker(lines.len()).chars().count() + 1;
+        Selection {
+            lines,
+            kept: vec![false; lines.len()],
+            // all lines are omitted, behind one marker
+            chars: if lines.is_empty() { 0 } else { marker_chars },
+            marker_chars,
+        }
+    }
+
+    /// Keep line `i` if it fits in `limit` characters in total. Returns whether the line is kept.
+    fn keep(&mut self, i: usize, limit: usize) -> bool {
+        if self.kept[i] {
+            return true;
+        }
+        // the omitted lines around `i` are split into up to two runs, each with a marker
+        let omitted_before = i > 0 && !self.kept[i - 1];
+        let omitted_after = i + 1 < self.lines.len() && !self.kept[i + 1];
+        let markers = usize::from(omitted_before) + usize::from(omitted_after);
+        let chars = self.chars + self.lines[i].chars().count() + 1 + markers * self.marker_chars
+            - self.marker_chars;
+        if chars > limit {
+            return false;
+        }
+        self.kept[i] = true;
+        self.chars = chars;
+        true
+    }
+}
+
+fn omitted_marker(count: usize) -> String {
+    format!("[... {} lines omitted ...]", count)
+}
+
+/// Truncate the output to at most `max_tokens`. Keeps the first lines, the snippets around errors
+/// (most recent errors first, or only the first line of an error if its snippet does not fit)
+/// and as many of the last lines as fit. Omitted lines are replaced with a `[... N lines omitted ...]` marker,
+/// whose size counts against `max_tokens`.
+pub fn truncate_output(output: &str, max_tokens: usize) -> String {
+    let max_chars = max_tokens * CHARS_PER_TOKEN;
+    let lines: Vec<String> = output.lines().map(shorten_line).collect();
+    let mut selection = Selection::new(&lines);
+    if selection.chars > max_chars {
+        // not even the marker fits
+        return String::new();
+    }
+    // a fifth of the budget for the head and two fifths for errors, the rest goes to the tail
+    for i in 0..lines.len() {
+        if !selection.keep(i, max_chars / 5) {
+            break;
+        }
+    }
+    let error_limit = selection.chars + max_chars * 2 / 5;
+    for snippet in find_snippets(output).iter().rev() {
+        let cost: usize = (snippet.start..snippet.end)
+            .filter(|&j| !selection.kept[j])
+            .map(|j| lines[j].chars().count() + 1)
+            .sum();
+        if selection.chars + cost + selection.marker_chars <= error_limit {
+            (snippet.start..snippet.end).for_each(|j| {
+                selection.keep(j, error_limit);
+            });
+        } else {
+            for diagnostic in snippet.diagnostics.iter().rev() {
+                selection.keep(diagnostic.start, error_limit);
+            }
+        }
+    }
+    for i in (0..lines.len()).rev() {
+        if !selection.keep(i, max_chars) {
+            break;
+        }
+    }
+
+    let mut truncated = Vec::new();
+    let mut omitted = 0;
+    for (line, kept) in lines.iter().zip(&selection.kept) {
+        if *kept {
+            if omitted > 0 {
+                truncated.push(omitted_marker(omitted));
+                omitted = 0;
+            }
+            truncated.push(line.clone());
+        } else {
+            omitted += 1;
+        }
+    }
+    if omitted > 0 {
+        truncated.push(omitted_marker(omitted));
+    }
+    truncated.join("\n")
+}
+
+/// Split the output into chunks of at most `max_chars` characters on line boundaries
+fn split_chunks(output: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_chars = 0;
+    for line in output.lines().map(shorten_line) {
+        let cost = line.chars().count() + 1;
+        if chunk_chars + cost > max_chars && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_chars = 0;
+        }
+        chunk.push_str(&line);
+        chunk.push('\n');
+        chunk_chars += cost;
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Condense the output to about `max_tokens` with the model. The output is split into chunks of
+/// up to `chunk_tokens`, which are summarized separately and joined. If the joined summaries are
+/// still too long, they are summarized again.
+pub fn summarize_output(
+    provider: &dyn Provider,
+    model: &Model,
+    context: &CommandContext,
+    chunk_tokens: usize,
+    max_tokens: usize,
+) -> Result<String, Box<dyn Error>> {
+    let chunk_chars = chunk_tokens.max(1) * CHARS_PER_TOKEN;
+    let line_count = context.output.lines().count();
+    let mut text = context.output.clone();
+    if estimate_tokens(&text) > chunk_tokens * MAX_SUMMARY_CHUNKS {
+        text = truncate_output(&text, chunk_tokens * MAX_SUMMARY_CHUNKS);
+    }
+    let command = context.command.as_deref().unwrap_or("unknown");
+    for _ in 0..MAX_SUMMARY_ROUNDS {
+        let chunks = split_chunks(&text, chunk_chars);
+        let mut summaries = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let messages = [
+                ChatMessage::system(SUMMARY_PROMPT),
+                ChatMessage::user(format!(
+                    "Command: {}\nPart {} of {} of the output:\n{}",
+                    command,
+                    i + 1,
+                    chunks.len(),
+                    chunk
+                )),
+            ];
+            let completion =
+                provider.stream_chat(&model.to_string(), &messages, &mut |_| Ok(()))?;
+            summaries.push(completion.content);
+        }
+        text = summaries.join("\n\n");
+        if estimate_tokens(&text) <= max_tokens {
+            break;
+        }
+    }
+    Ok(format!(
+        "[summary of {} lines of output]\n{}",
+        line_count, text
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build output with errors part way through, and some long lines
+    fn build_output(lines: usize) -> String {
+        (1..=lines)
+            .map(|i| match i % 97 {
+                0 => format!("error: step {} failed", i),
+                50 => "x".repeat(MAX_LINE_CHARS * 2),
+                _ => format!("line {} of the build output", i),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn truncates_within_the_budget() {
+        for lines in [1, 2, 10, 100, 1000, 5000] {
+            let output = build_output(lines);
+            for max_tokens in [0, 1, 5, 8, 10, 20, 50, 100, 333, 1000, 5000] {
+                let truncated = truncate_output(&output, max_tokens);
+                assert!(
+                    estimate_tokens(&truncated) <= max_tokens,
+                    "{} lines in {} tokens: {} tokens",
+                    lines,
+                    max_tokens,
+                    estimate_tokens(&truncated)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_start_errors_and_end() {
+        let output = build_output(1000);
+        let truncated = truncate_output(&output, 200);
+        let lines: Vec<&str> = truncated.lines().collect();
+        assert_eq!(lines[0], "line 1 of the build output");
+        assert_eq!(lines.last(), Some(&"line 1000 of the build output"));
+        // the most recent error first
+        assert!(truncated.contains("error: step 970 failed"));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("[... ") && line.ends_with(" lines omitted ...]")));
+    }
+
+    #[test]
+    fn keeps_output_which_fits() {
+        let output = build_output(10);
+        assert_eq!(truncate_output(&output, 1000), output);
+    }
+
+    #[test]
+    fn allots_unused_shares_to_larger_outputs() {
+        assert_eq!(allot(&[10, 100, 1000], 300), [10, 100, 190]);
+        assert_eq!(allot(&[1000, 1000], 300), [150, 150]);
+        assert_eq!(allot(&[10, 20], 300), [10, 20]);
+        assert_eq!(allot(&[], 300), [] as [usize; 0]);
+    }
+
+    #[test]
+    fn fits_contexts_in_the_budget() {
+        let mut contexts: Vec<CommandContext> = [5, 2000, 3000, 100]
+            .into_iter()
+            .map(|lines| CommandContext::from_output(build_output(lines)))
+            .collect();
+        let original: Vec<String> = contexts.iter().map(|c| c.output.clone()).collect();
+        let budget = 4000;
+        assert_eq!(fit_contexts(&mut contexts, budget, None), 2);
+        let sizes: Vec<usize> = contexts
+            .iter()
+            .map(|context| estimate_tokens(&context.output))
+            .collect();
+        assert!(sizes.iter().sum::<usize>() <= budget, "{:?}", sizes);
+        // the small outputs are kept, the large ones share the rest
+        assert_eq!(contexts[0].output, original[0]);
+        assert_eq!(contexts[3].output, original[3]);
+        let share = (budget - sizes[0] - sizes[3]) / 2;
+        assert!(
+            sizes[1] <= share && sizes[1] > share * 9 / 10,
+            "{:?}",
+            sizes
+        );
+        assert!(
+            sizes[2] <= share && sizes[2] > share * 9 / 10,
+            "{:?}",
+            sizes
+        );
+    }
+}
@@ -3,40 +3,67 @@ use std::{
     str::FromStr,
 };
 
-use clap::{command, Parser, Subcommand};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use serde::Deserialize;
 
 /// The default model to use for queries and chats.
 pub const DEFAULT_LLM: Model = Model::Gpt4o;
+/// The default LLM provider to use for queries and chats.
+pub const DEFAULT_PROVIDER: ProviderKind = ProviderKind::OpenAi;
 /// The default prompt to use for queries.
 pub const DEFAULT_QUERY: &str =
     "Here is the program output. If there was an error, concisely explain how it can be fixed. 
 If there was no error, concisely summarize the output.";
-/// A delimiter to indicate the end of a command, but prior to the output.
-// Ideally, there would be a more robust way to detect the end of a command
-// using stdin and detecting the end of a running subprocess in a shell.
+/// A delimiter written to the log when a command starts, prior to its output.
+// With shell integration, it is written when the shell marks the start of the command.
+// Otherwise the Enter key is used as a heuristic for the start of a new command.
 pub const NEW_COMMAND_MSG: &str = "<<<wtg:cmd-end>>>";
+/// Prefix of the command metadata (exit code, timing) written to the log when a command
+/// finishes. Followed by the metadata as JSON and `>>>`. Only written with shell integration.
+pub const COMMAND_META_MSG: &str = "<<<wtg:cmd-meta ";
 
-/// Various models supported by WTG
-#[derive(Debug, Clone, Copy)]
+/// Models known to WTG. Any other model identifier is passed through to the
+/// provider as is, so new models can be used without a WTG upgrade.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Model {
     Gpt4o,
     Gpt4oMini,
     O3Mini,
+    Other(String),
+}
+
+/// Optional metadata about a model
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModelInfo {
+    /// The maximum number of tokens (prompt and response) the model accepts
+    pub context_window: Option<usize>,
 }
 
 impl Model {
+    /// The known models (excluding aliases)
     pub fn all_models() -> Vec<String> {
         [Model::Gpt4o, Model::Gpt4oMini, Model::O3Mini]
             .iter()
             .map(|m| m.to_string())
             .collect()
     }
+
+    /// Metadata for the known models. Unknown models have no metadata.
+    pub fn info(&self) -> ModelInfo {
+        let context_window = match self {
+            Model::Gpt4o | Model::Gpt4oMini => Some(128_000),
+            Model::O3Mini => Some(200_000),
+            Model::Other(_) => None,
+        };
+        ModelInfo { context_window }
+    }
 }
 
 impl FromStr for Model {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
         match s.to_lowercase().as_str() {
             "gpt-4o" => Ok(Model::Gpt4o),
             "gpt4o" => Ok(Model::Gpt4o),
@@ -44,10 +71,8 @@ impl FromStr for Model {
             "gpt4o-mini" => Ok(Model::Gpt4oMini),
             "o3-mini" => Ok(Model::O3Mini),
             "o3mini" => Ok(Model::O3Mini),
-            _ => Err(format!(
-                "Invalid model: {}. Choose from: gpt-4o, gpt-4o-mini, o3-mini.",
-                s
-            )),
+            "" => Err("Model name cannot be empty.".to_string()),
+            _ => Ok(Model::Other(s.to_string())),
         }
     }
 }
@@ -58,10 +83,163 @@ impl Display for Model {
             Model::Gpt4o => write!(f, "gpt-4o"),
             Model::Gpt4oMini => write!(f, "gpt-4o-mini"),
             Model::O3Mini => write!(f, "o3-mini"),
+            Model::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// LLM providers supported by WTG
+#[derive(Debug, Clone, Copy)]
+pub enum ProviderKind {
+    OpenAi,
+}
+
+impl ProviderKind {
+    pub fn all_providers() -> Vec<String> {
+        [ProviderKind::OpenAi]
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    /// The env var holding the provider's API key
+    pub fn api_key_env(&self) -> &'static str {
+        match self {
+            ProviderKind::OpenAi => "WTG_OPENAI_KEY",
+        }
+    }
+}
+
+impl FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "openai" => Ok(ProviderKind::OpenAi),
+            _ => Err(format!(
+                "Invalid provider: {}. Choose from: {}.",
+                s,
+                ProviderKind::all_providers().join(", ")
+            )),
         }
     }
 }
 
+impl Display for ProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderKind::OpenAi => write!(f, "openai"),
+        }
+    }
+}
+
+/// Formats of the session log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The raw terminal output with [`NEW_COMMAND_MSG`] delimiters between commands
+    Text,
+    /// One JSON record per finished command (see [`crate::history::LogRecord`])
+    Jsonl,
+}
+
+impl LogFormat {
+    pub fn all_formats() -> Vec<String> {
+        [LogFormat::Text, LogFormat::Jsonl]
+            .iter()
+            .map(|f| f.to_string())
+            .collect()
+    }
+
+    /// The format implied by the log file's extension, `jsonl` for `.jsonl` files, otherwise `text`
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".jsonl") {
+            LogFormat::Jsonl
+        } else {
+            LogFormat::Text
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "jsonl" => Ok(LogFormat::Jsonl),
+            _ => Err(format!(
+                "Invalid log format: {}. Choose from: {}.",
+                s,
+                LogFormat::all_formats().join(", ")
+            )),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Jsonl => write!(f, "jsonl"),
+        }
+    }
+}
+
+/// A size in bytes, written as a number of bytes or with a `K`, `M` or `G` suffix (powers of 1024)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawByteSize")]
+pub struct ByteSize(pub u64);
+
+/// A size in the config file, either a number of bytes or a string like `"10M"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+    type Error = String;
+
+    fn try_from(raw: RawByteSize) -> Result<Self, Self::Error> {
+        match raw {
+            RawByteSize::Bytes(bytes) => Ok(ByteSize(bytes)),
+            RawByteSize::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("Invalid size: {}. Use e.g. 4096, 512K, 10M or 1G.", s);
+        if !s.is_ascii() {
+            return Err(invalid());
+        }
+        let (number, multiplier) = match s.to_uppercase().trim_end_matches(['B', 'I']) {
+            n if n.ends_with('K') => (&s[..n.len() - 1], 1 << 10),
+            n if n.ends_with('M') => (&s[..n.len() - 1], 1 << 20),
+            n if n.ends_with('G') => (&s[..n.len() - 1], 1 << 30),
+            n => (&s[..n.len()], 1),
+        };
+        number
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(ByteSize)
+            .ok_or_else(invalid)
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// CLI for `wtg`
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
@@ -77,25 +255,217 @@ pub enum Commands {
     /// logged to the file specified. This log file is also set as
     /// `WTG_LOG` env var.
     #[command(alias = "s")]
-    Start { logfile: String },
+    Start {
+        #[command(flatten)]
+        session: SessionArgs,
+        #[command(flatten)]
+        llm: LlmArgs,
+    },
     /// Queries GPT using the log file as context. Log file taken from
     /// CLI arg or `WTG_LOG` env var.
     #[command(alias = "q")]
     Query {
-        #[arg(short, long)]
-        logfile: Option<String>,
+        #[command(flatten)]
+        context: ContextArgs,
         #[arg(short, long)]
         prompt: Option<String>,
-        #[arg(short, long)]
-        model: Option<Model>,
+        #[command(flatten)]
+        llm: LlmArgs,
     },
     /// Start a chat session with the last command's output and all
     /// subsequent chat messages as context.
     #[command(alias = "c")]
     Chat {
+        #[command(flatten)]
+        context: ContextArgs,
+        #[command(flatten)]
+        llm: LlmArgs,
+    },
+    /// Lists the commands recorded in the log file, with the index
+    /// used to select them with `--cmd`. Log file taken from CLI arg
+    /// or `WTG_LOG` env var.
+    #[command(alias = "h")]
+    History {
         #[arg(short, long)]
         logfile: Option<String>,
-        #[arg(short, long)]
-        model: Option<Model>,
     },
+    /// Runs a single command in a pty without starting a shell session,
+    /// recording its output and exit status. Exits with the command's
+    /// exit status.
+    #[command(alias = "r")]
+    Run {
+        #[command(flatten)]
+        run: RunArgs,
+        #[command(flatten)]
+        llm: LlmArgs,
+    },
+    /// Pauses recording in the current WTG session, e.g. while editing
+    /// credentials. Nothing is logged until `wtg resume`.
+    Pause,
+    /// Resumes recording in the current WTG session.
+    Resume,
+}
+
+/// Options of a WTG session
+#[derive(Debug, Clone, ClapArgs)]
+pub struct SessionArgs {
+    pub logfile: String,
+    /// Don't install the bash/zsh/fish hooks which mark command boundaries.
+    /// New commands are then detected from the Enter key.
+    #[arg(long)]
+    pub no_shell_integration: bool,
+    /// The log format, `text` or `jsonl`. Defaults to `jsonl` if the log file ends in `.jsonl`,
+    /// otherwise `text`.
+    #[arg(long)]
+    pub format: Option<LogFormat>,
+    /// Also write the plain text log to this file, e.g. to follow a `jsonl` session log with `tail -f`
+    #[arg(long, value_name = "PATH")]
+    pub mirror: Option<String>,
+    /// Keep at most this much of each command's output (e.g. `1M`), half from the start and half
+    /// from the end. Falls back to `max_output_size` in the config, otherwise unlimited.
+    #[arg(long, value_name = "SIZE")]
+    pub max_output_size: Option<ByteSize>,
+    /// Once the log grows past this size (e.g. `100M`), move it to `<logfile>.1` and start a new
+    /// log. Falls back to `max_log_size` in the config, otherwise unlimited.
+    #[arg(long, value_name = "SIZE")]
+    pub max_log_size: Option<ByteSize>,
+    /// Only keep the N most recent commands in the log. Falls back to `keep_commands` in the
+    /// config, otherwise all commands are kept.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub keep_commands: Option<u64>,
+    /// When a command fails, stream a short diagnosis from the model below its output, using the
+    /// LLM options. Needs the shell integration. Falls back to `auto` in the config.
+    #[arg(long, conflicts_with = "no_shell_integration")]
+    pub auto: bool,
+    /// Diagnose at most one failed command every SECS seconds. Falls back to `auto_interval` in
+    /// the config, otherwise 30.
+    #[arg(long, value_name = "SECS")]
+    pub auto_interval: Option<u64>,
+    /// Don't diagnose failures of this program, in addition to `auto_ignore` in the config and
+    /// the built-in list (`grep`, `test`, `diff`, ...). Can be repeated.
+    #[arg(long, value_name = "PROGRAM")]
+    pub auto_ignore: Vec<String>,
+}
+
+impl SessionArgs {
+    pub fn format(&self) -> LogFormat {
+        self.format
+            .unwrap_or_else(|| LogFormat::from_path(&self.logfile))
+    }
+}
+
+/// Options of `wtg run`
+#[derive(Debug, Clone, ClapArgs)]
+pub struct RunArgs {
+    /// Append the command to this log file, `jsonl` if it ends in `.jsonl`, otherwise `text`.
+    /// Falls back to `WTG_LOG` outside a WTG session (a session already records the command).
+    #[arg(short, long)]
+    pub logfile: Option<String>,
+    /// If the command fails, ask the model to explain its output
+    #[arg(long)]
+    pub explain: bool,
+    /// The command and its arguments
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// Options selecting (and previewing) the logged commands used as context by queries and chats
+#[derive(Debug, Clone, ClapArgs)]
+pub struct ContextArgs {
+    #[arg(short, long)]
+    pub logfile: Option<String>,
+    /// The command to use as context. Negative values count back from the most recent
+    /// command (-1 is the most recent), positive values are the index of the command in the log.
+    #[arg(long, allow_negative_numbers = true, conflicts_with = "last")]
+    pub cmd: Option<i64>,
+    /// Use the N most recent commands as context
+    #[arg(long, value_name = "N")]
+    pub last: Option<usize>,
+    /// Summarize outputs which don't fit in the model's context window with extra model
+    /// requests, instead of only keeping their start, end and error lines
+    #[arg(long)]
+    pub summarize: bool,
+    /// Only send the lines around errors found in the output (compiler errors, stack traces,
+    /// failed tests, ...), with a summary of what was found. Outputs without errors are sent as is.
+    #[arg(long)]
+    pub focus: bool,
+    /// Print the secrets which would be redacted from the context (API keys, tokens, passwords,
+    /// ...) and exit without querying the model
+    #[arg(long)]
+    pub show_redactions: bool,
+    /// Print the messages which would be sent, with the model, endpoint and estimated token
+    /// count, and exit without any network request. `--summarize` is skipped.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// LLM options shared by queries, chats and diagnoses
+#[derive(Debug, Clone, ClapArgs)]
+pub struct LlmArgs {
+    /// The config file profile to use. Falls back to `WTG_PROFILE`, then `default_profile` in the config.
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// The LLM provider. Falls back to `WTG_PROVIDER`, the profile, then `openai`.
+    #[arg(long)]
+    pub provider: Option<ProviderKind>,
+    /// The model to query, any identifier the provider accepts. Falls back to `WTG_LLM`, the profile, then `gpt-4o`.
+    #[arg(short, long)]
+    pub model: Option<Model>,
+    /// The model's context window in tokens. Falls back to `WTG_CONTEXT_WINDOW`, the profile,
+    /// then the known value for the model (if any).
+    #[arg(long)]
+    pub context_window: Option<usize>,
+    /// Base URL of an OpenAI compatible API (e.g. `http://localhost:8080/v1`).
+    /// Falls back to `WTG_BASE_URL`, the profile, then the OpenAI API.
+    #[arg(long)]
+    pub base_url: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<u64, String> {
+        s.parse::<ByteSize>().map(|size| size.0)
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse("4096"), Ok(4096));
+        assert_eq!(parse("512K"), Ok(512 << 10));
+        assert_eq!(parse("10M"), Ok(10 << 20));
+        assert_eq!(parse("1G"), Ok(1 << 30));
+    }
+
+    #[test]
+    fn accepts_unit_spellings() {
+        for s in ["10m", "10M", "10MB", "10MiB", "10mib", " 10 M "] {
+            assert_eq!(parse(s), Ok(10 << 20), "{}", s);
+        }
+        assert_eq!(parse("100B"), Ok(100));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for s in ["", "M", "-1", "1.5M", "10T", "ten", "10MM", "１M"] {
+            assert!(parse(s).is_err(), "{}", s);
+        }
+        // overflows a u64
+        assert!(parse("18446744073709551615K").is_err());
+    }
+
+    #[test]
+    fn reads_sizes_from_config() {
+        #[derive(Deserialize)]
+        struct Limits {
+            bytes: ByteSize,
+            text: ByteSize,
+        }
+        let limits: Limits = toml::from_str("bytes = 4096\ntext = \"1G\"").unwrap();
+        assert_eq!(
+            (limits.bytes, limits.text),
+            (ByteSize(4096), ByteSize(1 << 30))
+        );
+        assert!(toml::from_str::<Limits>("bytes = 1\ntext = \"big\"").is_err());
+    }
 }
@@ -0,0 +1,211 @@
+//! The optional `wtg` configuration file.
+//!
+//! The config file is read from `WTG_CONFIG` if set, otherwise from
+//! `$XDG_CONFIG_HOME/wtg/config.toml` (or `~/.config/wtg/config.toml`). It holds named
+//! profiles which group LLM settings, for example:
+//!
+//! ```toml
+//! default_profile = "work"
+//!
+//! [profiles.work]
+//! provider = "openai"
+//! model = "gpt-4o"
+//! base_url = "https://llm-gateway.internal/v1"
+//! key_command = "pass show llm-gateway"
+//!
+//! [profiles.local]
+//! model = "llama3"
+//! base_url = "http://localhost:8080/v1"
+//! context_window = 8192
+//!
+//! [session]
+//! max_output_size = "1M"
+//! max_log_size = "100M"
+//! keep_commands = 500
+//! auto = true
+//! auto_ignore = ["make"]
+//!
+//! [redaction.patterns]
+//! internal-token = "itk_[A-Za-z0-9]{32}"
+//! db-password = 'DB_PASS=(?P<secret>\S+)'
+//! ```
+//!
+//! Profile values sit between env vars and the hard coded defaults, i.e. the precedence is
+//! (1) the CLI argument, (2) the env var, (3) the selected profile, (4) the default.
+//! Session values are used if the option is not given on the CLI. Redaction patterns are added
+//! to the built-in ones, see [`crate::redact`].
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::cli::ByteSize;
+use crate::errors::WtgError;
+
+/// The parsed config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The profile used when neither `--profile` nor `WTG_PROFILE` is set
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub redaction: RedactionConfig,
+}
+
+/// A named group of LLM settings
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    /// The default prompt for queries
+    pub prompt: Option<String>,
+    /// A shell command which prints the API key (e.g. `pass show openai`), used over the
+    /// provider's key env var
+    pub key_command: Option<String>,
+    /// Send the provider's key env var (e.g. `WTG_OPENAI_KEY`) to the profile's `base_url`,
+    /// which otherwise only gets the key from `key_command`
+    #[serde(default)]
+    pub use_env_key: bool,
+    pub context_window: Option<usize>,
+}
+
+/// Defaults for `wtg start` options
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    pub max_output_size: Option<ByteSize>,
+    pub max_log_size: Option<ByteSize>,
+    #[serde(default, deserialize_with = "at_least_one")]
+    pub keep_commands: Option<u64>,
+    /// Diagnose failed commands, like `--auto`
+    pub auto: Option<bool>,
+    /// Seconds between diagnoses
+    pub auto_interval: Option<u64>,
+    /// Programs whose failures are not diagnosed, added to the `--auto-ignore` ones
+    #[serde(default)]
+    pub auto_ignore: Vec<String>,
+}
+
+/// Reject 0, like the CLI option does
+fn at_least_one<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(0) => Err(de::Error::custom("must be at least 1")),
+        value => Ok(value),
+    }
+}
+
+/// Secret redaction settings
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedactionConfig {
+    /// Extra secret regexes by name, redacted as `[REDACTED:<name>]`
+    #[serde(default)]
+    pub patterns: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// The config file path, from `WTG_CONFIG` or the XDG config directory
+    pub fn path() -> Option<PathBuf> {
+        if let Ok(path) = env::var("WTG_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let config_dir = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok()?;
+        Some(config_dir.join("wtg").join("config.toml"))
+    }
+
+    /// Load the config file. A missing file is an empty config, unless the path was
+    /// explicitly set with `WTG_CONFIG`.
+    pub fn load() -> Result<Self, WtgError> {
+        let Some(path) = Self::path() else {
+            return Ok(Config::default());
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) if env::var("WTG_CONFIG").is_err() && !path.exists() => {
+                return Ok(Config::default())
+            }
+            Err(e) => {
+                return Err(WtgError::ConfigError {
+                    path: path.display().to_string(),
+                    message: e.to_string(),
+                })
+            }
+        };
+        toml::from_str(&content).map_err(|e| WtgError::ConfigError {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Get the profile selected by the CLI arg, then the `WTG_PROFILE` env var, then
+    /// `default_profile`. If no profile is selected, an empty profile is returned.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, WtgError> {
+        let name = name
+            .map(str::to_string)
+            .or_else(|| env::var("WTG_PROFILE").ok())
+            .or_else(|| self.default_profile.clone());
+        match name {
+            Some(name) => self
+                .profiles
+                .get(&name)
+                .cloned()
+                .ok_or(WtgError::ProfileNotFound { profile: name }),
+            None => Ok(Profile::default()),
+        }
+    }
+}
+
+impl Profile {
+    /// Run the profile's `key_command` (if any) and return its trimmed stdout
+    pub fn api_key(&self) -> Result<Option<String>, WtgError> {
+        let Some(key_command) = &self.key_command else {
+            return Ok(None);
+        };
+        let output = Command::new("sh").arg("-c").arg(key_command).output()?;
+        if !output.status.success() {
+            return Err(WtgError::KeyCommandFailed {
+                command: key_command.clone(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(Some(
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_keep_commands() {
+        let config: Config = toml::from_str("[session]\nkeep_commands = 500").unwrap();
+        assert_eq!(config.session.keep_commands, Some(500));
+        let config: Config = toml::from_str("[session]").unwrap();
+        assert_eq!(config.session.keep_commands, None);
+    }
+
+    #[test]
+    fn rejects_keeping_no_commands() {
+        let error = toml::from_str::<Config>("[session]\nkeep_commands = 0").unwrap_err();
+        assert!(
+            error.to_string().contains("must be at least 1"),
+            "{}",
+            error
+        );
+    }
+}
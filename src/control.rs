@@ -0,0 +1,410 @@
+//! The control socket of a WTG session.
+//!
+//! `wtg start` listens on a Unix domain socket, whose path is exported to the session shell as
+//! `WTG_SOCK`. Clients send one JSON [`Request`] per line and receive one JSON [`Response`] per
+//! line, for example:
+//!
+//! ```text
+//! {"cmd":"list","last":2}
+//! {"ok":true,"commands":[{"index":-2,"command":"make","exit_code":2,...},{"index":-1,...}]}
+//! {"cmd":"get","index":-1}
+//! {"ok":true,"commands":[{"index":-1,"command":"ls","exit_code":0,...,"output":"..."}]}
+//! {"cmd":"pause"}
+//! {"ok":true}
+//! ```
+//!
+//! The session answers from the commands it keeps in memory, which gives clients the exact
+//! command boundaries without parsing the log. Commands are indexed from the end, `-1` is the
+//! most recently finished command. Only the commands recorded by the running session are kept,
+//! so clients fall back to the log for older commands.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::WtgError;
+use crate::history::{CommandIndex, CommandMeta, CommandRecord, LogRecord};
+use crate::recorder::Recorder;
+use crate::shell::SessionDir;
+
+/// A request to the session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    /// List the `last` most recent commands (by default all kept in memory), without their
+    /// output unless `output` is set
+    List {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last: Option<usize>,
+        #[serde(default)]
+        output: bool,
+    },
+    /// Get a command with its output, by its negative index
+    Get { index: i64 },
+    /// Stop recording, like `wtg pause`
+    Pause,
+    /// Resume recording, like `wtg resume`
+    Resume,
+    /// End the running command's recorded output here and record the rest as a new command,
+    /// named `label` if given
+    Mark {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+    },
+}
+
+/// A command kept by the session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandEntry {
+    /// Position from the end, `-1` is the most recent command
+    pub index: i64,
+    #[serde(flatten)]
+    pub meta: CommandMeta,
+    /// The raw terminal output, starting with the prompt line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+/// The session's answer to a request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commands: Option<Vec<CommandEntry>>,
+}
+
+impl Response {
+    fn ok() -> Self {
+        Response {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Response {
+            error: Some(message.into()),
+            ..Default::default()
+        }
+    }
+
+    fn commands(commands: Vec<CommandEntry>) -> Self {
+        Response {
+            ok: true,
+            commands: Some(commands),
+            ..Default::default()
+        }
+    }
+}
+
+/// The session's control socket. The socket file is removed when this is dropped.
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ControlServer {
+    /// Listen on a new socket in the session directory, which is only accessible to the current
+    /// user
+    pub fn bind(dir: &SessionDir) -> Result<Self, WtgError> {
+        let path = dir.path().join("control.sock");
+        let listener = UnixListener::bind(&path)?;
+        Ok(ControlServer { path, listener })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Answer requests in the background, each connection on its own thread
+    pub fn serve(&self, recorder: Arc<Mutex<Recorder>>) -> io::Result<()> {
+        let listener = self.listener.try_clone()?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let recorder = Arc::clone(&recorder);
+                std::thread::spawn(move || {
+                    let _ = handle_connection(stream, &recorder);
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn handle_connection(stream: UnixStream, recorder: &Mutex<Recorder>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_line(&line, recorder);
+        let mut line = serde_json::to_string(&response).expect("Failed to serialize response");
+        line.push('\n');
+        writer.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Answer a request line, the recorder is only locked for valid requests
+fn handle_line(line: &str, recorder: &Mutex<Recorder>) -> Response {
+    match serde_json::from_str::<Request>(line) {
+        Ok(request) => handle_request(request, &mut recorder.lock().unwrap()),
+        Err(e) => Response::error(format!("Invalid request: {}", e)),
+    }
+}
+
+fn handle_request(request: Request, recorder: &mut Recorder) -> Response {
+    let entries = |count: Option<usize>, output: bool| -> Vec<CommandEntry> {
+        let records: Vec<&LogRecord> = recorder.recent_commands(count).collect();
+        let total = records.len() as i64;
+        records
+            .into_iter()
+            .enumerate()
+            .map(|(i, record)| CommandEntry {
+                index: i as i64 - total,
+                meta: record.meta.clone(),
+                output: output.then(|| record.output.clone()),
+            })
+            .collect()
+    };
+    let result = match request {
+        Request::List { last, output } => return Response::commands(entries(last, output)),
+        Request::Get { index } if index < 0 => {
+            let mut found = entries(Some(index.unsigned_abs() as usize), true);
+            if found.len() < index.unsigned_abs() as usize {
+                return Response::error(format!(
+                    "Command {} not found, the session has {} command(s) in memory.",
+                    index,
+                    recorder.recent_commands(None).count()
+                ));
+            }
+            found.truncate(1);
+            return Response::commands(found);
+        }
+        Request::Get { index } => {
+            return Response::error(format!(
+                "Invalid index {}, commands are indexed from the end (-1 is the most recent).",
+                index
+            ))
+        }
+        Request::Pause => recorder.set_paused(true),
+        Request::Resume => recorder.set_paused(false),
+        Request::Mark { label } => match recorder.mark(label) {
+            Ok(false) => return Response::error("No command is running."),
+            result => result.map(|_| ()),
+        },
+    };
+    match result {
+        Ok(()) => Response::ok(),
+        Err(e) => Response::error(format!("Failed to write to the log: {}", e)),
+    }
+}
+
+/// A connection to the control socket of the enclosing session
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl ControlClient {
+    /// Connect to the socket in `WTG_SOCK`. Returns `None` if it is not set or the session is
+    /// gone.
+    pub fn from_env() -> Option<Self> {
+        let stream = UnixStream::connect(env::var_os("WTG_SOCK")?).ok()?;
+        let writer = stream.try_clone().ok()?;
+        Some(ControlClient {
+            reader: BufReader::new(stream),
+            writer,
+        })
+    }
+
+    /// Send a request and wait for the response. A response with an error is returned as
+    /// [`WtgError::ControlError`].
+    pub fn request(&mut self, request: &Request) -> Result<Response, WtgError> {
+        let mut line = serde_json::to_string(request).expect("Failed to serialize request");
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|e| WtgError::ControlError(format!("Invalid response: {}", e)))?;
+        if !response.ok {
+            return Err(WtgError::ControlError(response.error.unwrap_or_default()));
+        }
+        Ok(response)
+    }
+
+    /// The `count` most recent commands with their output, oldest first. Returns fewer commands
+    /// if the session has fewer in memory.
+    pub fn recent_commands(&mut self, count: usize) -> Result<Vec<CommandRecord>, WtgError> {
+        let response = self.request(&Request::List {
+            last: Some(count),
+            output: true,
+        })?;
+        Ok(response
+            .commands
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| {
+                let record = LogRecord {
+                    meta: entry.meta,
+                    output: entry.output.unwrap_or_default(),
+                };
+                CommandRecord::from_log_record(
+                    CommandIndex::FromEnd(entry.index.unsigned_abs() as usize),
+                    record,
+                )
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::recorder::LogLimits;
+
+    /// A recorder which has recorded the commands, with their exit codes, through the shell
+    /// integration markers
+    fn recorder(commands: &[(&str, i32)]) -> Mutex<Recorder> {
+        let mut recorder = Recorder::new(None, None, LogLimits::default());
+        for (command, exit_code) in commands {
+            let output = format!(
+                "\x1b]133;A\x07$ \x1b]133;C;cmdline_url={}\x07{} output\r\n\x1b]133;D;{}\x07",
+                command, command, exit_code
+            );
+            recorder.on_output(output.as_bytes()).unwrap();
+        }
+        Mutex::new(recorder)
+    }
+
+    /// The index, command, exit code and output of each command in the response
+    fn commands(response: &Response) -> Vec<(i64, &str, Option<i32>, Option<&str>)> {
+        assert!(response.ok, "{:?}", response.error);
+        response
+            .commands
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.index,
+                    entry.meta.command.as_deref().unwrap_or_default(),
+                    entry.meta.exit_code,
+                    entry.output.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    fn error(response: &Response) -> &str {
+        assert!(!response.ok);
+        response.error.as_deref().unwrap()
+    }
+
+    #[test]
+    fn lists_commands() {
+        let recorder = recorder(&[("make", 2), ("ls", 0), ("pwd", 0)]);
+        let response = handle_line(r#"{"cmd":"list"}"#, &recorder);
+        assert_eq!(
+            commands(&response),
+            [
+                (-3, "make", Some(2), None),
+                (-2, "ls", Some(0), None),
+                (-1, "pwd", Some(0), None)
+            ]
+        );
+        let response = handle_line(r#"{"cmd":"list","last":2,"output":true}"#, &recorder);
+        assert_eq!(
+            commands(&response),
+            [
+                (-2, "ls", Some(0), Some("$ ls output\r\n")),
+                (-1, "pwd", Some(0), Some("$ pwd output\r\n"))
+            ]
+        );
+        // more than are kept
+        let response = handle_line(r#"{"cmd":"list","last":10}"#, &recorder);
+        assert_eq!(commands(&response).len(), 3);
+    }
+
+    #[test]
+    fn gets_commands_by_index() {
+        let recorder = recorder(&[("make", 2), ("ls", 0)]);
+        let response = handle_line(r#"{"cmd":"get","index":-1}"#, &recorder);
+        assert_eq!(
+            commands(&response),
+            [(-1, "ls", Some(0), Some("$ ls output\r\n"))]
+        );
+        let response = handle_line(r#"{"cmd":"get","index":-2}"#, &recorder);
+        assert_eq!(
+            commands(&response),
+            [(-2, "make", Some(2), Some("$ make output\r\n"))]
+        );
+        let response = handle_line(r#"{"cmd":"get","index":-3}"#, &recorder);
+        assert_eq!(
+            error(&response),
+            "Command -3 not found, the session has 2 command(s) in memory."
+        );
+        for index in [0, 1] {
+            let line = format!(r#"{{"cmd":"get","index":{}}}"#, index);
+            assert!(error(&handle_line(&line, &recorder)).starts_with("Invalid index"));
+        }
+    }
+
+    #[test]
+    fn pauses_and_marks() {
+        let recorder = recorder(&[]);
+        assert!(handle_line(r#"{"cmd":"pause"}"#, &recorder).ok);
+        assert!(handle_line(r#"{"cmd":"resume"}"#, &recorder).ok);
+        let response = handle_line(r#"{"cmd":"mark","label":"part 2"}"#, &recorder);
+        assert_eq!(error(&response), "No command is running.");
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let recorder = recorder(&[("ls", 0)]);
+        for line in [
+            "not json",
+            r#"{"cmd":"delete"}"#,
+            r#"{"index":-1}"#,
+            r#"{"cmd":"get"}"#,
+            r#"{"cmd":"get","index":"last"}"#,
+            r#"{"cmd":"list","limit":1}"#,
+        ] {
+            let response = handle_line(line, &recorder);
+            assert!(
+                error(&response).starts_with("Invalid request: "),
+                "{}",
+                line
+            );
+            assert_eq!(response.commands.map(|commands| commands.len()), None);
+        }
+    }
+
+    #[test]
+    fn serializes_requests_as_documented() {
+        let line = serde_json::to_string(&Request::List {
+            last: Some(2),
+            output: false,
+        })
+        .unwrap();
+        assert_eq!(line, r#"{"cmd":"list","last":2,"output":false}"#);
+        let line = serde_json::to_string(&Response::error("failed")).unwrap();
+        assert_eq!(line, r#"{"ok":false,"error":"failed"}"#);
+    }
+}
@@ -0,0 +1,539 @@
+//! Find errors in command output.
+//!
+//! The output is scanned line by line for the start of a diagnostic, and each diagnostic
+//! is extended to the end of its block (e.g. the source snippet of a rustc error or the frames
+//! of a stack trace). Recognized formats:
+//! - rustc errors and Rust panics
+//! - gcc/clang errors and linker errors
+//! - Python tracebacks
+//! - Java exceptions with their `at` frames and `Caused by` chains
+//! - Go panics with their goroutine stacks
+//! - `npm ERR!` blocks
+//! - failed tests (`FAILED`, `--- FAIL`, `FAIL`, `AssertionError`)
+//! - any other line which mentions an error, unless it is a zero count or negated (e.g.
+//!   `0 failed`, `no errors found`)
+//!
+//! [`find_snippets`] adds the lines around each diagnostic and merges overlapping ranges, and
+//! [`focus_output`] reduces an output to these snippets.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// Lines kept before and after each diagnostic
+const CONTEXT_LINES: usize = 3;
+/// The longest diagnostic block, longer blocks (e.g. deep stack traces) are cut off
+const MAX_BLOCK_LINES: usize = 40;
+/// Case insensitive words of other lines which likely describe an error
+const ERROR_WORDS: &[&str] = &[
+    "error",
+    "errors",
+    "fatal",
+    "panic",
+    "panicked",
+    "exception",
+    "exceptions",
+    "failed",
+    "failure",
+    "failures",
+    "denied",
+    "undefined",
+];
+/// Words before an error word which negate it, e.g. `no errors`
+const NEGATIONS: &[&str] = &["0", "no", "zero", "without", "never"];
+
+/// The kind of a diagnostic, by the tool which likely printed it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiagnosticKind {
+    RustcError,
+    RustPanic,
+    CompilerError,
+    PythonTraceback,
+    JavaException,
+    GoPanic,
+    NpmError,
+    TestFailure,
+    ErrorLine,
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::RustcError => write!(f, "rustc error"),
+            DiagnosticKind::RustPanic => write!(f, "Rust panic"),
+            DiagnosticKind::CompilerError => write!(f, "compiler error"),
+            DiagnosticKind::PythonTraceback => write!(f, "Python traceback"),
+            DiagnosticKind::JavaException => write!(f, "Java exception"),
+            DiagnosticKind::GoPanic => write!(f, "Go panic"),
+            DiagnosticKind::NpmError => write!(f, "npm error"),
+            DiagnosticKind::TestFailure => write!(f, "test failure"),
+            DiagnosticKind::ErrorLine => write!(f, "error line"),
+        }
+    }
+}
+
+/// A diagnostic found in the output, as a range of line indices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// The first line, e.g. the `error[E0308]: mismatched types` line
+    pub start: usize,
+    /// One past the last line
+    pub end: usize,
+}
+
+/// Diagnostics with the lines around them, as a range of line indices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    pub start: usize,
+    /// One past the last line
+    pub end: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Find the diagnostics in the output, in order
+pub fn find_diagnostics(output: &str) -> Vec<Diagnostic> {
+    let lines: Vec<&str> = output.lines().collect();
+    let mut diagnostics = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        match detect(&lines, i) {
+            Some(diagnostic) => {
+                i = diagnostic.end;
+                diagnostics.push(diagnostic);
+            }
+            None => i += 1,
+        }
+    }
+    diagnostics
+}
+
+/// Find the diagnostics in the output, with [`CONTEXT_LINES`] lines around each. Overlapping
+/// snippets are merged.
+pub fn find_snippets(output: &str) -> Vec<Snippet> {
+    let line_count = output.lines().count();
+    let mut snippets: Vec<Snippet> = Vec::new();
+    for diagnostic in find_diagnostics(output) {
+        let start = diagnostic.start.saturating_sub(CONTEXT_LINES);
+        let end = (diagnostic.end + CONTEXT_LINES).min(line_count);
+        match snippets.last_mut() {
+            Some(last) if start <= last.end => {
+                last.end = last.end.max(end);
+                last.diagnostics.push(diagnostic);
+            }
+            _ => snippets.push(Snippet {
+                start,
+                end,
+                diagnostics: vec![diagnostic],
+            }),
+        }
+    }
+    snippets
+}
+
+/// Reduce the output to the snippets around its diagnostics, after a header summarizing what
+/// was found. Omitted lines are replaced with a `[... N lines omitted ...]` marker. Returns
+/// `None` if no diagnostics were found.
+pub fn focus_output(output: &str) -> Option<String> {
+    let snippets = find_snippets(output);
+    if snippets.is_empty() {
+        return None;
+    }
+    let lines: Vec<&str> = output.lines().collect();
+    let mut counts: BTreeMap<DiagnosticKind, usize> = BTreeMap::new();
+    for diagnostic in snippets.iter().flat_map(|snippet| &snippet.diagnostics) {
+        *counts.entry(diagnostic.kind).or_default() += 1;
+    }
+    let found = counts
+        .iter()
+        .map(|(kind, count)| format!("{} x {}", count, kind))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut focused = vec![format!(
+        "[wtg: showing the lines around {} in {} lines of output]",
+        found,
+        lines.len()
+    )];
+    let mut next = 0;
+    for snippet in &snippets {
+        if snippet.start > next {
+            focused.push(format!("[... {} lines omitted ...]", snippet.start - next));
+        }
+        focused.extend(
+            lines[snippet.start..snippet.end]
+                .iter()
+                .map(|line| line.to_string()),
+        );
+        next = snippet.end;
+    }
+    if lines.len() > next {
+        focused.push(format!("[... {} lines omitted ...]", lines.len() - next));
+    }
+    Some(focused.join("\n"))
+}
+
+/// Detect a diagnostic starting at line `i`
+fn detect(lines: &[&str], i: usize) -> Option<Diagnostic> {
+    let line = lines[i];
+    let trimmed = line.trim_start();
+    let (kind, end) = if is_rustc_error(lines, i) {
+        // the source snippet and notes end at a blank line
+        (
+            DiagnosticKind::RustcError,
+            extend(lines, i, |line| !line.trim().is_empty()),
+        )
+    } else if trimmed.starts_with("thread '") && trimmed.contains("' panicked at") {
+        // the panic message, up to the `note: run with RUST_BACKTRACE=1` hint or backtrace
+        let message_end = extend(lines, i, |line| {
+            !line.trim().is_empty() && !line.starts_with("note:")
+        });
+        let end = match lines.get(message_end) {
+            Some(line) if line.starts_with("note:") => message_end + 1,
+            _ => message_end,
+        };
+        (DiagnosticKind::RustPanic, end)
+    } else if is_compiler_error(line) {
+        // followed by the source line and caret, and any notes
+        (
+            DiagnosticKind::CompilerError,
+            extend(lines, i, |line| {
+                is_source_excerpt(line) || line.contains(": note:")
+            }),
+        )
+    } else if trimmed.starts_with("Traceback (most recent call last)") {
+        // the frames are indented, the exception follows them
+        let frames_end = extend(lines, i, |line| line.starts_with([' ', '\t']));
+        (
+            DiagnosticKind::PythonTraceback,
+            (frames_end + 1).min(lines.len()),
+        )
+    } else if is_java_exception(trimmed) {
+        (
+            DiagnosticKind::JavaException,
+            extend(lines, i, |line| {
+                let line = line.trim_start();
+                line.starts_with("at ") || line.starts_with("Caused by:") || line.starts_with("...")
+            }),
+        )
+    } else if trimmed.starts_with("panic: ") || trimmed.starts_with("fatal error: ") {
+        // the goroutine stacks follow a blank line
+        let mut end = i + 1;
+        while end < lines.len()
+            && end - i < MAX_BLOCK_LINES
+            && (is_go_stack_line(lines[end])
+                || (lines[end].trim().is_empty()
+                    && lines
+                        .get(end + 1)
+                        .is_some_and(|next| next.starts_with("goroutine "))))
+        {
+            end += 1;
+        }
+        (DiagnosticKind::GoPanic, end)
+    } else if trimmed.starts_with("npm ERR!") || trimmed.starts_with("npm error") {
+        (
+            DiagnosticKind::NpmError,
+            extend(lines, i, |line| {
+                line.starts_with("npm ERR!") || line.starts_with("npm error")
+            }),
+        )
+    } else if is_test_failure(trimmed) {
+        (DiagnosticKind::TestFailure, i + 1)
+    } else if is_error_line(line) {
+        (DiagnosticKind::ErrorLine, i + 1)
+    } else {
+        return None;
+    };
+    Some(Diagnostic {
+        kind,
+        start: i,
+        end,
+    })
+}
+
+/// The end of the block starting at line `start`, which continues while lines match `pred`
+fn extend(lines: &[&str], start: usize, pred: impl Fn(&str) -> bool) -> usize {
+    let mut end = start + 1;
+    while end < lines.len() && end - start < MAX_BLOCK_LINES && pred(lines[end]) {
+        end += 1;
+    }
+    end
+}
+
+/// `error[E0308]: ...`, or `error: ...` followed by the ` --> file:line:column` location
+fn is_rustc_error(lines: &[&str], i: usize) -> bool {
+    let line = lines[i].trim_start();
+    line.starts_with("error[E")
+        || (line.starts_with("error:")
+            && lines
+                .get(i + 1)
+                .is_some_and(|next| next.trim_start().starts_with("--> ")))
+}
+
+/// A source line or caret line quoted by gcc/clang, e.g. `    5 |   foo x;` or `      |   ^~~`
+fn is_source_excerpt(line: &str) -> bool {
+    line.trim_start()
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .trim_start()
+        .starts_with('|')
+}
+
+/// A line of a Go goroutine stack: the goroutine header, a call, its tab indented location,
+/// and the `exit status` printed by `go run`
+fn is_go_stack_line(line: &str) -> bool {
+    line.starts_with("goroutine ")
+        || line.starts_with('\t')
+        || line.starts_with("created by ")
+        || line.starts_with("[signal ")
+        || line.starts_with("exit status ")
+        || (!line.starts_with(' ') && line.ends_with(')'))
+}
+
+/// `file.c:12:5: error: ...` (gcc/clang), or a linker error
+fn is_compiler_error(line: &str) -> bool {
+    if line.contains("undefined reference to") || line.starts_with("ld: ") {
+        return true;
+    }
+    let Some(pos) = line
+        .find(": error:")
+        .or_else(|| line.find(": fatal error:"))
+    else {
+        return false;
+    };
+    // the location is `<file>:<line>` optionally followed by `:<column>`
+    let mut location = line[..pos].rsplit(':');
+    let last = location.next().unwrap_or("");
+    let numeric = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    numeric(last) && location.next().is_some()
+}
+
+/// `Exception in thread "main" java.lang.IllegalStateException: ...` or
+/// `java.lang.NullPointerException: ...`
+fn is_java_exception(line: &str) -> bool {
+    if line.starts_with("Exception in thread ") {
+        return true;
+    }
+    let name = line.split(':').next().unwrap_or("");
+    !name.contains(' ')
+        && name.contains('.')
+        && (name.ends_with("Exception") || name.ends_with("Error"))
+}
+
+fn is_test_failure(line: &str) -> bool {
+    line.contains("FAILED")
+        || line.starts_with("--- FAIL")
+        || line.starts_with("FAIL ")
+        || line.contains("AssertionError")
+}
+
+/// Whether a line likely describes an error: it has an error word (or a name such as
+/// `TypeError`) which is not a zero count or negated, or says something was `not found`. Words
+/// are separated by whitespace and trimmed of punctuation, so names such as `error-chain` or
+/// `error.rs` are not error words.
+fn is_error_line(line: &str) -> bool {
+    let raw: Vec<&str> = line.split_whitespace().collect();
+    let words: Vec<&str> = raw
+        .iter()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .collect();
+    let lowercase: Vec<String> = words.iter().map(|word| word.to_lowercase()).collect();
+    (0..words.len()).any(|i| {
+        let word = lowercase[i].as_str();
+        if word == "found" && i > 0 && lowercase[i - 1] == "not" {
+            return true;
+        }
+        let is_error = ERROR_WORDS.contains(&word)
+            || (words[i].len() > "Error".len()
+                && (words[i].ends_with("Error") || words[i].ends_with("Exception")));
+        // `0 failed`, `no errors`, `Errors: 0`
+        let negated = i > 0 && NEGATIONS.contains(&lowercase[i - 1].as_str());
+        let zero_count =
+            raw[i].ends_with([':', '=']) && lowercase.get(i + 1).is_some_and(|next| next == "0");
+        is_error && !negated && !zero_count
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kind and line range of each diagnostic found
+    fn found(output: &str) -> Vec<(DiagnosticKind, usize, usize)> {
+        find_diagnostics(output)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.kind, diagnostic.start, diagnostic.end))
+            .collect()
+    }
+
+    #[test]
+    fn finds_rustc_errors() {
+        let output = "\
+   Compiling demo v0.1.0 (/tmp/demo)
+error[E0308]: mismatched types
+ --> src/main.rs:2:18
+  |
+2 |     let x: i32 = \"a\";
+  |            ---   ^^^ expected `i32`, found `&str`
+
+error: could not compile `demo` (bin \"demo\") due to 1 previous error";
+        assert_eq!(
+            found(output),
+            [
+                (DiagnosticKind::RustcError, 1, 6),
+                (DiagnosticKind::ErrorLine, 7, 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_rust_panics() {
+        let output = "\
+running
+thread 'main' panicked at src/main.rs:2:5:
+index out of bounds
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+done";
+        assert_eq!(found(output), [(DiagnosticKind::RustPanic, 1, 4)]);
+    }
+
+    #[test]
+    fn finds_compiler_errors() {
+        let output = "\
+main.c: In function 'main':
+main.c:3:5: error: unknown type name 'foo'
+    3 |     foo x;
+      |     ^~~
+main.c:3:5: note: declared here
+/usr/bin/ld: main.o: in function `main': undefined reference to `bar'";
+        assert_eq!(
+            found(output),
+            [
+                (DiagnosticKind::CompilerError, 1, 5),
+                (DiagnosticKind::CompilerError, 5, 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_python_tracebacks() {
+        let output = "\
+Traceback (most recent call last):
+  File \"app.py\", line 3, in <module>
+    main()
+  File \"app.py\", line 1, in main
+    foo()
+NameError: name 'foo' is not defined
+$ ";
+        assert_eq!(found(output), [(DiagnosticKind::PythonTraceback, 0, 6)]);
+    }
+
+    #[test]
+    fn finds_java_exceptions() {
+        let output = "\
+Exception in thread \"main\" java.lang.IllegalStateException: bad state
+\tat Main.run(Main.java:5)
+\tat Main.main(Main.java:2)
+Caused by: java.io.IOException: closed
+\t... 2 more
+java.lang.NullPointerException: null
+\tat Main.main(Main.java:9)";
+        assert_eq!(
+            found(output),
+            [
+                (DiagnosticKind::JavaException, 0, 5),
+                (DiagnosticKind::JavaException, 5, 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_go_panics() {
+        let output = "\
+panic: runtime error: index out of range [3] with length 2
+
+goroutine 1 [running]:
+main.main()
+\t/tmp/main.go:5 +0x1d
+exit status 2
+$ ";
+        assert_eq!(found(output), [(DiagnosticKind::GoPanic, 0, 6)]);
+    }
+
+    #[test]
+    fn finds_npm_errors() {
+        let output = "\
+> npm install
+npm ERR! code ENOENT
+npm ERR! syscall open
+npm ERR! path /app/package.json
+added 0 packages";
+        assert_eq!(found(output), [(DiagnosticKind::NpmError, 1, 4)]);
+    }
+
+    #[test]
+    fn finds_test_failures() {
+        let output = "\
+--- FAIL: TestAdd (0.00s)
+FAIL example.com/calc 0.01s
+FAILED tests/test_calc.py::test_add - assert 1 == 2
+E   AssertionError: expected 3
+test result: FAILED. 1 passed; 1 failed; 0 ignored";
+        let kinds: Vec<DiagnosticKind> = found(output).into_iter().map(|(kind, ..)| kind).collect();
+        assert_eq!(kinds, [DiagnosticKind::TestFailure; 5]);
+    }
+
+    #[test]
+    fn finds_other_error_lines() {
+        for line in [
+            "bash: foo: command not found",
+            "cat: /etc/shadow: Permission denied",
+            "TypeError: x is undefined",
+            "Build failed with 2 errors",
+            "Errors: 3",
+        ] {
+            assert_eq!(found(line), [(DiagnosticKind::ErrorLine, 0, 1)], "{}", line);
+        }
+    }
+
+    #[test]
+    fn ignores_passing_output() {
+        let output = "\
+   Compiling error-chain v0.12.4
+   Compiling demo v0.1.0 (/tmp/demo)
+    Finished `test` profile [unoptimized + debuginfo] target(s) in 1.20s
+     Running unittests src/lib.rs (target/debug/deps/demo-0123456789abcdef)
+
+running 2 tests
+test tests::handles_error_input ... ok
+test tests::no_panic_on_empty ... ok
+
+test result: ok. 2 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s
+Tests run: 5, Failures: 0, Errors: 0, Skipped: 0
+Found 0 errors in 3 files
+No errors found
+Success: no issues found in 3 source files
+src/error.rs: 0 problems (0 errors, 0 warnings)
+ok  \texample.com/calc\t0.01s";
+        assert_eq!(found(output), []);
+        assert_eq!(focus_output(output), None);
+    }
+
+    #[test]
+    fn focuses_on_the_lines_around_errors() {
+        let mut lines: Vec<String> = (1..=20).map(|i| format!("line {}", i)).collect();
+        lines[10] = "error: disk full".to_string();
+        let focused = focus_output(&lines.join("\n")).unwrap();
+        assert_eq!(
+            focused,
+            "\
+[wtg: showing the lines around 1 x error line in 20 lines of output]
+[... 7 lines omitted ...]
+line 8
+line 9
+line 10
+error: disk full
+line 12
+line 13
+line 14
+[... 6 lines omitted ...]"
+        );
+    }
+}
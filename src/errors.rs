@@ -5,12 +5,30 @@ use thiserror::Error;
 pub enum WtgError {
     #[error("No command run yet in this session.")]
     NoCommandRun { logfile: String },
+    #[error("Command {cmd} not found, the session has recorded {count} command(s).")]
+    CommandNotFound { cmd: i64, count: usize },
     #[error("Chat should have stdin connected to a tty, otherwise input is not interactive.")]
     ChatNotTty,
+    #[error("Not inside a wtg session, start one with `wtg s <logfile>`.")]
+    NotInSession,
+    #[error("No log file given, pass `--logfile` or start a session with `wtg s <logfile>`.")]
+    NoLogFile,
+    #[error("Session control request failed: {0}")]
+    ControlError(String),
     #[error("Nix error: {0}")]
     NixError(#[from] nix::Error),
     #[error("Failed to open log file: {logfile}. Does it exist?")]
     LogFileOpenError { logfile: String },
+    #[error("Failed to set up LLM provider: {0}")]
+    ProviderError(String),
+    #[error("Invalid config file {path}: {message}")]
+    ConfigError { path: String, message: String },
+    #[error("Invalid redaction pattern `{name}`: {message}")]
+    InvalidRedactionPattern { name: String, message: String },
+    #[error("Profile {profile} not found in the config file.")]
+    ProfileNotFound { profile: String },
+    #[error("Key command `{command}` failed: {stderr}")]
+    KeyCommandFailed { command: String, stderr: String },
     #[error(transparent)]
     StdioError(#[from] std::io::Error),
 }
@@ -0,0 +1,1021 @@
+//! Parse the session log into the commands it has recorded.
+//!
+//! The log is in one of two formats (see [`LogFormat`]), detected when it is read by
+//! [`read_commands`]:
+//! - `text`: the raw terminal output. The session writes [`NEW_COMMAND_MSG`] to the log when a
+//!   new command starts, so the log is split into one segment per command on these delimiters.
+//!   With shell integration, the session also writes the command's [`CommandMeta`] when it
+//!   finishes.
+//! - `jsonl`: one [`LogRecord`] per line, written when each command finishes.
+//!
+//! Logs of long sessions can be large, so queries about recent commands read the log backwards
+//! from the end with [`read_recent_commands`], which only loads the commands it returns. Commands
+//! are first found from their metadata, and [`select_commands`] only renders the output of the
+//! commands it selects.
+//!
+//! `wtg` invocations recorded in the log (flagged in their [`CommandMeta`]) are skipped, so
+//! earlier answers are never taken for the output of a command. Commands are indexed without them.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{LogFormat, COMMAND_META_MSG, NEW_COMMAND_MSG};
+use crate::errors::WtgError;
+use crate::llm::CommandContext;
+use crate::sanitize::render_terminal_output;
+
+/// Metadata of a finished command, reported by the shell integration hooks
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CommandMeta {
+    /// The command line, if it was reported by the shell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// The working directory the command was run in, if it was reported by the shell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    pub exit_code: Option<i32>,
+    /// Start time as seconds since the Unix epoch
+    pub started_at: u64,
+    pub duration_ms: u64,
+    /// Set for `wtg` invocations, which are skipped when reading the log
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub wtg: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl CommandMeta {
+    /// The log line recording this metadata
+    pub fn to_log_line(&self) -> String {
+        format!(
+            "{}{}>>>\n",
+            COMMAND_META_MSG,
+            serde_json::to_string(self).expect("Failed to serialize command metadata")
+        )
+    }
+
+    pub fn failed(&self) -> bool {
+        self.exit_code.is_some_and(|code| code != 0)
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// Summary of how the command finished, e.g. "exit code 2 after 14s"
+    pub fn summary(&self) -> String {
+        match self.exit_code {
+            Some(code) => format!(
+                "exit code {} after {}",
+                code,
+                format_duration(self.duration())
+            ),
+            None => format!("finished after {}", format_duration(self.duration())),
+        }
+    }
+}
+
+/// A finished command, as written to a `jsonl` log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    #[serde(flatten)]
+    pub meta: CommandMeta,
+    /// The raw terminal output, starting with the prompt line. Invalid UTF-8 is replaced.
+    pub output: String,
+}
+
+impl LogRecord {
+    /// The log line recording this command
+    pub fn to_log_line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("Failed to serialize log record");
+        line.push('\n');
+        line
+    }
+}
+
+/// Human readable duration, e.g. "850ms", "4.2s", "14s" or "3m12s"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else if secs < 10 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m{}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
+
+/// How many of the most recent commands are searched for a failing command, when no command
+/// is selected
+pub const FAILURE_SEARCH_DEPTH: usize = 50;
+
+/// The size of the first read from the end of the log, each further read doubles the size
+const TAIL_CHUNK: u64 = 64 * 1024;
+/// The start of each output rendered by [`read_previews`], enough for the first lines
+const PREVIEW_SIZE: usize = 8 * 1024;
+
+/// Position of a command in the log, in the form accepted by `--cmd`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandIndex {
+    /// 1-based position from the start of the log
+    FromStart(usize),
+    /// 1-based position from the end of the log, 1 is the most recent command
+    FromEnd(usize),
+}
+
+impl Display for CommandIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandIndex::FromStart(n) => write!(f, "{}", n),
+            CommandIndex::FromEnd(n) => write!(f, "-{}", n),
+        }
+    }
+}
+
+/// A command recorded in the session log
+#[derive(Debug, Clone)]
+pub struct CommandRecord {
+    /// Position of the command in the log. Commands read from the end of the log only know
+    /// their position from the end.
+    pub index: CommandIndex,
+    /// The prompt line (with the typed command) followed by the command output,
+    /// rendered to the visible text (see [`render_terminal_output`])
+    pub output: String,
+    /// Set if the log recorded how the command finished
+    pub meta: Option<CommandMeta>,
+}
+
+impl CommandRecord {
+    pub fn from_log_record(index: CommandIndex, record: LogRecord) -> Self {
+        CommandRecord {
+            index,
+            output: render_terminal_output(&record.output),
+            meta: Some(record.meta),
+        }
+    }
+
+    /// The command and its output to use as context. The status is left out if the exit code is
+    /// unknown, since the duration then runs from one Enter to the next and includes idle time.
+    pub fn to_context(&self) -> CommandContext {
+        CommandContext {
+            command: self.command().map(str::to_string),
+            status: self
+                .meta
+                .as_ref()
+                .filter(|meta| meta.exit_code.is_some())
+                .map(CommandMeta::summary),
+            output: self.output.clone(),
+        }
+    }
+
+    /// Whether the command is a `wtg` invocation
+    pub fn is_wtg(&self) -> bool {
+        self.meta.as_ref().is_some_and(|meta| meta.wtg)
+    }
+
+    /// The command line reported by the shell, if known
+    pub fn command(&self) -> Option<&str> {
+        self.meta.as_ref().and_then(|meta| meta.command.as_deref())
+    }
+
+    /// The command line reported by the shell, otherwise the prompt line with the typed command
+    pub fn command_line(&self) -> &str {
+        self.command()
+            .unwrap_or_else(|| self.output.lines().next().unwrap_or(""))
+            .trim()
+    }
+
+    /// The first non-empty line of output after the command line
+    pub fn first_output_line(&self) -> &str {
+        self.output
+            .lines()
+            .skip(1)
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or("")
+    }
+
+    /// Size of the recorded output in bytes
+    pub fn size(&self) -> usize {
+        self.output.len()
+    }
+}
+
+fn open_log(logfile: &str) -> Result<File, WtgError> {
+    File::open(logfile).map_err(|_| WtgError::LogFileOpenError {
+        logfile: logfile.to_string(),
+    })
+}
+
+/// Read all commands recorded in a log file of either format, oldest first
+pub fn read_commands(logfile: &str) -> Result<Vec<CommandRecord>, WtgError> {
+    let log = ScannedLog::read_all(logfile)?;
+    Ok(log.load(0..log.entries.len()))
+}
+
+/// Read the `count` most recent commands recorded in a log file of either format, oldest first.
+/// The log is read backwards from the end until it holds enough commands. Returns fewer commands
+/// if the log has fewer.
+pub fn read_recent_commands(logfile: &str, count: usize) -> Result<Vec<CommandRecord>, WtgError> {
+    let log = ScannedLog::read_recent(logfile, count)?;
+    Ok(log.load(0..log.entries.len()))
+}
+
+/// A command listed by `wtg history`
+#[derive(Debug, Clone)]
+pub struct CommandPreview {
+    /// The command with only the start of its output
+    pub record: CommandRecord,
+    /// Size of the whole recorded output in bytes
+    pub size: usize,
+}
+
+/// Read all commands recorded in a log file, oldest first, only rendering the start of their
+/// output
+pub fn read_previews(logfile: &str) -> Result<Vec<CommandPreview>, WtgError> {
+    let log = ScannedLog::read_all(logfile)?;
+    Ok((0..log.entries.len()).map(|i| log.preview(i)).collect())
+}
+
+/// A command found in the log, before its output is parsed
+#[derive(Debug, Clone)]
+struct LogEntry {
+    meta: Option<CommandMeta>,
+    /// The bytes of the command in the log content
+    span: Range<usize>,
+}
+
+/// Log content with the commands found in it. Finding the commands only reads their metadata,
+/// the output of a command is only parsed and rendered when it is loaded.
+struct ScannedLog {
+    content: String,
+    format: LogFormat,
+    /// The commands, oldest first, without `wtg` invocations
+    entries: Vec<LogEntry>,
+    /// Set if the content is the end of the log, so commands are indexed from the end
+    from_end: bool,
+}
+
+impl ScannedLog {
+    fn read_all(logfile: &str) -> Result<Self, WtgError> {
+        let mut file = open_log(logfile)?;
+        let format = detect_format(&mut file)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        Ok(Self::scan(
+            String::from_utf8_lossy(&content).into_owned(),
+            format,
+            false,
+        ))
+    }
+
+    /// Read the log backwards from the end until it holds the `count` most recent commands
+    fn read_recent(logfile: &str, count: usize) -> Result<Self, WtgError> {
+        let mut file = open_log(logfile)?;
+        let format = detect_format(&mut file)?;
+        let len = file.seek(SeekFrom::End(0))?;
+        let mut buf = Vec::new();
+        let mut buf_start = len;
+        loop {
+            // a start at the beginning of the buffer may be part way through a command
+            let starts: Vec<usize> = command_starts(&buf, format)
+                .into_iter()
+                .filter(|&start| start > 0 || buf_start == 0)
+                .collect();
+            // one more than needed, since the last command may still be running
+            if starts.len() > count || buf_start == 0 {
+                let from = starts.first().copied().unwrap_or(buf.len());
+                let content = String::from_utf8_lossy(&buf[from..]).into_owned();
+                let mut log = Self::scan(content, format, true);
+                // skipped `wtg` invocations may leave too few commands, then read further back
+                if log.entries.len() >= count || buf_start == 0 {
+                    log.entries.drain(..log.entries.len().saturating_sub(count));
+                    return Ok(log);
+                }
+            }
+            let chunk_len = TAIL_CHUNK.max(buf.len() as u64).min(buf_start);
+            buf_start -= chunk_len;
+            file.seek(SeekFrom::Start(buf_start))?;
+            let mut chunk = vec![0; chunk_len as usize];
+            file.read_exact(&mut chunk)?;
+            chunk.append(&mut buf);
+            buf = chunk;
+        }
+    }
+
+    /// Find the commands in the content from their metadata. The last segment of a `text` log
+    /// is usually the running command (e.g. the `wtg query` itself) and is only included if it
+    /// has finished.
+    fn scan(content: String, format: LogFormat, from_end: bool) -> Self {
+        let mut entries = Vec::new();
+        match format {
+            LogFormat::Text => {
+                let mut bounds = text_command_starts(content.as_bytes());
+                bounds.push(content.len());
+                for window in bounds.windows(2) {
+                    let span = window[0]..window[1];
+                    let meta = segment_meta(&content[span.clone()]);
+                    entries.push(LogEntry { meta, span });
+                }
+                if entries.last().is_some_and(|entry| entry.meta.is_none()) {
+                    entries.pop();
+                }
+            }
+            LogFormat::Jsonl => {
+                let mut start = 0;
+                for line in content.split_inclusive('\n') {
+                    let span = start..start + line.len();
+                    start = span.end;
+                    // the output field is skipped, lines which are not a record are ignored
+                    if let Ok(meta) = serde_json::from_str::<CommandMeta>(line) {
+                        entries.push(LogEntry {
+                            meta: Some(meta),
+                            span,
+                        });
+                    }
+                }
+            }
+        }
+        entries.retain(|entry| !entry.meta.as_ref().is_some_and(|meta| meta.wtg));
+        ScannedLog {
+            content,
+            format,
+            entries,
+            from_end,
+        }
+    }
+
+    fn metas(&self) -> Vec<Option<&CommandMeta>> {
+        self.entries
+            .iter()
+            .map(|entry| entry.meta.as_ref())
+            .collect()
+    }
+
+    fn index(&self, i: usize) -> CommandIndex {
+        if self.from_end {
+            CommandIndex::FromEnd(self.entries.len() - i)
+        } else {
+            CommandIndex::FromStart(i + 1)
+        }
+    }
+
+    /// The raw output and metadata of the `i`th command
+    fn raw(&self, i: usize) -> (String, Option<CommandMeta>) {
+        let entry = &self.entries[i];
+        let text = &self.content[entry.span.clone()];
+        match self.format {
+            LogFormat::Text => split_segment(text),
+            LogFormat::Jsonl => match serde_json::from_str::<LogRecord>(text) {
+                Ok(record) => (record.output, Some(record.meta)),
+                // a record without output
+                Err(_) => (String::new(), entry.meta.clone()),
+            },
+        }
+    }
+
+    /// Parse and render the commands in `range`
+    fn load(&self, range: Range<usize>) -> Vec<CommandRecord> {
+        range
+            .map(|i| {
+                let (output, meta) = self.raw(i);
+                CommandRecord {
+                    index: self.index(i),
+                    output: render_terminal_output(&output),
+                    meta,
+                }
+            })
+            .collect()
+    }
+
+    /// The `i`th command with only the start of its output rendered
+    fn preview(&self, i: usize) -> CommandPreview {
+        let (output, meta) = self.raw(i);
+        let mut end = output.len().min(PREVIEW_SIZE);
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        CommandPreview {
+            record: CommandRecord {
+                index: self.index(i),
+                output: render_terminal_output(&output[..end]),
+                meta,
+            },
+            size: output.len(),
+        }
+    }
+}
+
+/// Split a `text` log segment into its raw output and metadata, removing the delimiter and
+/// metadata lines
+fn split_segment(segment: &str) -> (String, Option<CommandMeta>) {
+    // strip the `wtg` inserted `NEW_COMMAND_MSG` delimiter from the output
+    let mut output = segment.replace(NEW_COMMAND_MSG, "");
+    let mut meta = None;
+    while let Some(start) = output.find(COMMAND_META_MSG) {
+        let json_start = start + COMMAND_META_MSG.len();
+        let mut stream =
+            serde_json::Deserializer::from_str(&output[json_start..]).into_iter::<CommandMeta>();
+        let parsed = stream.next().and_then(Result::ok);
+        let json_end = json_start + stream.byte_offset();
+        let end = if output[json_end..].starts_with(">>>") {
+            json_end + 3
+        } else {
+            json_end
+        };
+        let end = if output[end..].starts_with('\n') {
+            end + 1
+        } else {
+            end
+        };
+        meta = parsed.or(meta);
+        output.replace_range(start..end.max(json_start), "");
+    }
+    (output, meta)
+}
+
+/// The metadata of a `text` log segment (the last one if there are several), without copying
+/// its output
+fn segment_meta(segment: &str) -> Option<CommandMeta> {
+    segment
+        .match_indices(COMMAND_META_MSG)
+        .filter_map(|(start, _)| {
+            serde_json::Deserializer::from_str(&segment[start + COMMAND_META_MSG.len()..])
+                .into_iter::<CommandMeta>()
+                .next()?
+                .ok()
+        })
+        .last()
+}
+
+/// A log is `jsonl` if it starts with a [`LogRecord`]
+fn detect_format(file: &mut File) -> Result<LogFormat, WtgError> {
+    let first_record = serde_json::Deserializer::from_reader(BufReader::new(&mut *file))
+        .into_iter::<LogRecord>()
+        .next();
+    file.rewind()?;
+    Ok(match first_record {
+        Some(Ok(_)) => LogFormat::Jsonl,
+        _ => LogFormat::Text,
+    })
+}
+
+/// Parse the records of a `jsonl` log, oldest first. Lines which are not a record (e.g. a
+/// partially written last line) and `wtg` invocations are skipped.
+pub fn parse_records(log_content: &str) -> Vec<CommandRecord> {
+    let log = ScannedLog::scan(log_content.to_string(), LogFormat::Jsonl, false);
+    log.load(0..log.entries.len())
+}
+
+/// Byte offsets where each command recorded in the log starts, oldest first
+pub fn command_starts(log: &[u8], format: LogFormat) -> Vec<usize> {
+    match format {
+        LogFormat::Text => text_command_starts(log),
+        LogFormat::Jsonl => {
+            let mut starts: Vec<usize> = log
+                .iter()
+                .enumerate()
+                .filter(|&(_, &b)| b == b'\n')
+                .map(|(i, _)| i + 1)
+                .collect();
+            // the last newline ends the last record rather than starting a new one
+            if starts.last() == Some(&log.len()) {
+                starts.pop();
+            }
+            if !log.is_empty() {
+                starts.insert(0, 0);
+            }
+            starts
+        }
+    }
+}
+
+/// Byte offsets where each command recorded in the log starts, oldest first, without the `wtg`
+/// invocations (see [`command_starts`])
+pub fn user_command_starts(log: &[u8], format: LogFormat) -> Vec<usize> {
+    let is_wtg = |span: Range<usize>| {
+        let meta = match format {
+            LogFormat::Text => segment_meta(&String::from_utf8_lossy(&log[span])),
+            LogFormat::Jsonl => serde_json::from_slice::<CommandMeta>(&log[span]).ok(),
+        };
+        meta.is_some_and(|meta| meta.wtg)
+    };
+    let starts = command_starts(log, format);
+    let ends = starts.iter().skip(1).copied().chain([log.len()]);
+    starts
+        .iter()
+        .copied()
+        .zip(ends)
+        .filter(|&(start, end)| !is_wtg(start..end))
+        .map(|(start, _)| start)
+        .collect()
+}
+
+/// Each command spans roughly from its `NEW_COMMAND_MSG` to the next one. Also takes the entire
+/// line of the starting `NEW_COMMAND_MSG`, since the delimiter is written after the prompt
+/// and typed command when commands are detected from the Enter key.
+fn text_command_starts(log: &[u8]) -> Vec<usize> {
+    let marker = NEW_COMMAND_MSG.as_bytes();
+    let markers: Vec<usize> = log
+        .windows(marker.len())
+        .enumerate()
+        .filter(|&(_, window)| window == marker)
+        .map(|(idx, _)| idx)
+        .collect();
+    // start of the line containing each delimiter, but never before the previous delimiter
+    markers
+        .iter()
+        .enumerate()
+        .map(|(i, &idx)| {
+            let line_start = log[..idx]
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            let prev_end = i
+                .checked_sub(1)
+                .map(|prev| markers[prev] + marker.len())
+                .unwrap_or(0);
+            line_start.max(prev_end)
+        })
+        .collect()
+}
+
+/// Split the `text` log content into the recorded commands, oldest first (see
+/// [`command_starts`]). The segment following the last `NEW_COMMAND_MSG` is usually the
+/// currently running command (e.g. the `wtg query` itself) and is only included if it has finished.
+/// Finished `wtg` invocations are skipped.
+pub fn parse_commands(log_content: &str) -> Vec<CommandRecord> {
+    let log = ScannedLog::scan(log_content.to_string(), LogFormat::Text, false);
+    log.load(0..log.entries.len())
+}
+
+/// Select the commands to use as context from the log.
+///
+/// - `cmd`: a negative value counts back from the most recent command (`-1` is the most recent),
+///   a positive value is the 1-based index of the command in the log.
+/// - `last`: the number of most recent commands to select.
+///
+/// If neither is given, selects the most recent failing command among the last
+/// [`FAILURE_SEARCH_DEPTH`] commands, or the most recent command if none is known to have failed.
+/// Only a positive `cmd` reads the whole log.
+pub fn select_commands(
+    logfile: &str,
+    cmd: Option<i64>,
+    last: Option<usize>,
+) -> Result<Vec<CommandRecord>, WtgError> {
+    let log = match recent_count(cmd, last) {
+        Some(count) => ScannedLog::read_recent(logfile, count)?,
+        None => ScannedLog::read_all(logfile)?,
+    };
+    // only the output of the selected commands is rendered
+    let selected = select_range(&log.metas(), logfile, cmd, last)?;
+    Ok(log.load(selected))
+}
+
+/// How many of the most recent commands [`select_commands`] needs, or `None` if it needs all
+/// commands
+pub fn recent_count(cmd: Option<i64>, last: Option<usize>) -> Option<usize> {
+    match (cmd, last) {
+        (Some(cmd), _) if cmd < 0 => Some(cmd.unsigned_abs() as usize),
+        (Some(_), _) => None,
+        (None, Some(last)) => Some(last.max(1)),
+        (None, None) => Some(FAILURE_SEARCH_DEPTH),
+    }
+}
+
+/// Select the commands as [`select_commands`] does, from the records read from `logfile`. The
+/// records must be the [`recent_count`] most recent commands.
+pub fn select_from(
+    records: Vec<CommandRecord>,
+    logfile: &str,
+    cmd: Option<i64>,
+    last: Option<usize>,
+) -> Result<Vec<CommandRecord>, WtgError> {
+    let metas: Vec<Option<&CommandMeta>> =
+        records.iter().map(|record| record.meta.as_ref()).collect();
+    let selected = select_range(&metas, logfile, cmd, last)?;
+    Ok(records
+        .into_iter()
+        .skip(selected.start)
+        .take(selected.len())
+        .collect())
+}
+
+/// The positions of the selected commands, given the metadata of the commands read from
+/// `logfile` (see [`select_from`])
+fn select_range(
+    metas: &[Option<&CommandMeta>],
+    logfile: &str,
+    cmd: Option<i64>,
+    last: Option<usize>,
+) -> Result<Range<usize>, WtgError> {
+    if metas.is_empty() {
+        return Err(WtgError::NoCommandRun {
+            logfile: logfile.to_string(),
+        });
+    }
+    let not_found = |cmd: i64| WtgError::CommandNotFound {
+        cmd,
+        count: metas.len(),
+    };
+    let idx = match (cmd, last) {
+        (Some(cmd), _) if cmd < 0 => {
+            // fewer commands were read than requested
+            if metas.len() < cmd.unsigned_abs() as usize {
+                return Err(not_found(cmd));
+            }
+            0
+        }
+        (Some(cmd), _) => (cmd as usize)
+            .checked_sub(1)
+            .filter(|&idx| idx < metas.len())
+            .ok_or_else(|| not_found(cmd))?,
+        (None, Some(_)) => return Ok(0..metas.len()),
+        (None, None) => metas
+            .iter()
+            .rposition(|meta| meta.is_some_and(CommandMeta::failed))
+            .unwrap_or(metas.len() - 1),
+    };
+    Ok(idx..idx + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// A log file in the temp directory, removed when dropped
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str, content: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("wtg-history-{}-{}", std::process::id(), name));
+            std::fs::write(&path, content).unwrap();
+            TempLog(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn meta(i: usize, exit_code: i32, wtg: bool) -> CommandMeta {
+        CommandMeta {
+            command: Some(format!("cmd {}", i)),
+            exit_code: Some(exit_code),
+            started_at: i as u64,
+            duration_ms: 5,
+            wtg,
+            ..Default::default()
+        }
+    }
+
+    /// A command as the recorder writes it to a `text` log, the metadata starts a new line
+    fn text_command(meta: &CommandMeta, output: &str) -> String {
+        let line_end = if output.ends_with('\n') { "" } else { "\n" };
+        format!(
+            "{}$ {}\r\n{}{}{}",
+            NEW_COMMAND_MSG,
+            meta.command.as_deref().unwrap_or_default(),
+            output,
+            line_end,
+            meta.to_log_line()
+        )
+    }
+
+    /// A command as the recorder writes it to a `jsonl` log
+    fn jsonl_command(meta: &CommandMeta, output: &str) -> String {
+        LogRecord {
+            meta: meta.clone(),
+            output: format!(
+                "$ {}\r\n{}",
+                meta.command.as_deref().unwrap_or_default(),
+                output
+            ),
+        }
+        .to_log_line()
+    }
+
+    /// A log of `count` commands with outputs of varying sizes, every 7th a `wtg` invocation
+    fn long_log(command: fn(&CommandMeta, &str) -> String, count: usize) -> String {
+        (1..=count)
+            .map(|i| {
+                let output = format!("output of {}\r\n", i).repeat(i * 37 % 200);
+                command(&meta(i, (i % 3) as i32, i % 7 == 0), &output)
+            })
+            .collect()
+    }
+
+    fn commands(records: &[CommandRecord]) -> Vec<(&str, Option<&str>)> {
+        records
+            .iter()
+            .map(|record| {
+                (
+                    record.output.as_str(),
+                    record
+                        .meta
+                        .as_ref()
+                        .and_then(|meta| meta.command.as_deref()),
+                )
+            })
+            .collect()
+    }
+
+    /// `read_recent_commands` returns the tail of `read_commands`, indexed from the end
+    fn assert_reads_tail(log: &TempLog, counts: &[usize]) {
+        let all = read_commands(log.path()).unwrap();
+        for &count in counts {
+            assert_reads_recent(log, &all, count);
+        }
+    }
+
+    fn assert_reads_recent(log: &TempLog, all: &[CommandRecord], count: usize) {
+        let recent = read_recent_commands(log.path(), count).unwrap();
+        assert_eq!(
+            commands(&recent),
+            commands(&all[all.len().saturating_sub(count)..]),
+            "count {}",
+            count
+        );
+        let indexes: Vec<CommandIndex> = recent.iter().map(|record| record.index).collect();
+        let expected: Vec<CommandIndex> = (1..=recent.len())
+            .rev()
+            .map(CommandIndex::FromEnd)
+            .collect();
+        assert_eq!(indexes, expected);
+    }
+
+    #[test]
+    fn reads_recent_commands_of_long_logs() {
+        for (name, command) in [
+            ("long.log", text_command as fn(&CommandMeta, &str) -> String),
+            ("long.jsonl", jsonl_command),
+        ] {
+            let content = long_log(command, 300);
+            assert!(content.len() as u64 > 4 * TAIL_CHUNK);
+            let log = TempLog::new(name, &content);
+            assert_reads_tail(&log, &[1, 2, 5, 17, 60, 150, 257, 400]);
+            // the `wtg` invocations are skipped
+            let all = read_commands(log.path()).unwrap();
+            assert_eq!(all.len(), 300 - 300 / 7);
+            assert!(all.iter().all(|record| !record.meta.as_ref().unwrap().wtg));
+        }
+    }
+
+    #[test]
+    fn reads_commands_split_across_chunks() {
+        let first = text_command(&meta(1, 0, false), "first\r\n");
+        let second_meta = meta(2, 1, false);
+        let delimiter_end = NEW_COMMAND_MSG.len();
+        let meta_len = second_meta.to_log_line().len();
+        // the end of the second-to-last chunk falls on each byte of the last command's
+        // delimiter, then of the markers around the previous command's metadata
+        let chunk = TAIL_CHUNK as usize;
+        let lengths = (chunk..chunk + delimiter_end + 1)
+            .chain(chunk - meta_len - 1..chunk - meta_len + COMMAND_META_MSG.len() + 1)
+            .chain(chunk - ">>>\n".len() - 1..chunk);
+        for length in lengths {
+            let last_meta = meta(3, 0, false);
+            let padding = length - text_command(&last_meta, "").len();
+            let last = text_command(&last_meta, &"x".repeat(padding));
+            assert_eq!(last.len(), length);
+            let content = first.clone() + &text_command(&second_meta, "second\r\n") + &last;
+            let log = TempLog::new("split.log", &content);
+            assert_reads_tail(&log, &[1, 2, 3, 4]);
+            let recent = read_recent_commands(log.path(), 2).unwrap();
+            assert_eq!(commands(&recent)[0], ("$ cmd 2\nsecond\n", Some("cmd 2")));
+            assert_eq!(
+                recent[1].output,
+                format!("$ cmd 3\n{}\n", "x".repeat(padding))
+            );
+        }
+    }
+
+    #[test]
+    fn skips_the_running_command() {
+        // the last command (e.g. the `wtg q` reading the log) has not finished
+        let content = text_command(&meta(1, 0, false), "one\r\n")
+            + &text_command(&meta(2, 2, false), "two\r\n")
+            + NEW_COMMAND_MSG
+            + "$ wtg q\r\n";
+        let log = TempLog::new("running.log", &content);
+        let recent = read_recent_commands(log.path(), 1).unwrap();
+        assert_eq!(commands(&recent), [("$ cmd 2\ntwo\n", Some("cmd 2"))]);
+        assert_eq!(read_commands(log.path()).unwrap().len(), 2);
+        // a partially written `jsonl` record is skipped
+        let content = jsonl_command(&meta(1, 0, false), "one\r\n")
+            + &jsonl_command(&meta(2, 2, false), "two\r\n")
+            + "{\"exit_code\":0,\"out";
+        let log = TempLog::new("running.jsonl", &content);
+        let recent = read_recent_commands(log.path(), 1).unwrap();
+        assert_eq!(commands(&recent), [("$ cmd 2\ntwo\n", Some("cmd 2"))]);
+    }
+
+    #[test]
+    fn skips_wtg_invocations_at_the_end() {
+        for (name, command) in [
+            ("wtg.log", text_command as fn(&CommandMeta, &str) -> String),
+            ("wtg.jsonl", jsonl_command),
+        ] {
+            let mut content = long_log(command, 40);
+            // more `wtg` output than a chunk, so the reader must go further back
+            for i in 41..44 {
+                content += &command(&meta(i, 0, true), &"answer\r\n".repeat(4000));
+            }
+            let log = TempLog::new(name, &content);
+            let recent = read_recent_commands(log.path(), 2).unwrap();
+            let names: Vec<Option<&str>> = commands(&recent).into_iter().map(|c| c.1).collect();
+            assert_eq!(names, [Some("cmd 39"), Some("cmd 40")]);
+            assert_reads_tail(&log, &[2]);
+        }
+    }
+
+    #[test]
+    fn parses_text_segments() {
+        let content = "output before the first command\n".to_string()
+            + &text_command(&meta(1, 0, false), "\x1b[31mred\x1b[0m\r\n")
+            + NEW_COMMAND_MSG
+            + "$ no metadata\r\n";
+        // without metadata, the last segment is still running
+        let records = parse_commands(&content);
+        assert_eq!(commands(&records), [("$ cmd 1\nred\n", Some("cmd 1"))]);
+        assert_eq!(records[0].index, CommandIndex::FromStart(1));
+        // segments without metadata (no shell integration) are kept before the last
+        let content = format!("{0}$ ls\r\nfile\r\n{0}$ pwd\r\n/\r\n{0}", NEW_COMMAND_MSG);
+        let records = parse_commands(&content);
+        assert_eq!(
+            commands(&records),
+            [("$ ls\nfile\n", None), ("$ pwd\n/\n", None)]
+        );
+    }
+
+    #[test]
+    fn finds_command_starts() {
+        let text = format!("pre\n$ ls{0}\nout\n{0}$ pwd", NEW_COMMAND_MSG);
+        // the first delimiter was written after the typed command, so its line is included
+        assert_eq!(
+            command_starts(text.as_bytes(), LogFormat::Text),
+            [4, 4 + 4 + NEW_COMMAND_MSG.len() + 5]
+        );
+        assert_eq!(command_starts(b"{}\n{}\n", LogFormat::Jsonl), [0, 3]);
+        assert_eq!(command_starts(b"{}\n{", LogFormat::Jsonl), [0, 3]);
+        assert_eq!(command_starts(b"", LogFormat::Jsonl), [] as [usize; 0]);
+    }
+
+    #[test]
+    fn leaves_out_unknown_exit_statuses() {
+        let record = |exit_code| CommandRecord {
+            index: CommandIndex::FromEnd(1),
+            output: "$ make
+"
+            .to_string(),
+            meta: Some(CommandMeta {
+                exit_code,
+                duration_ms: 1000,
+                command: Some("make".to_string()),
+                ..Default::default()
+            }),
+        };
+        let context = record(Some(2)).to_context();
+        assert_eq!(context.status.as_deref(), Some("exit code 2 after 1.0s"));
+        let context = record(None).to_context();
+        assert_eq!(context.command.as_deref(), Some("make"));
+        assert_eq!(context.status, None);
+    }
+
+    fn records(exit_codes: &[Option<i32>]) -> Vec<CommandRecord> {
+        exit_codes
+            .iter()
+            .enumerate()
+            .map(|(i, &exit_code)| CommandRecord {
+                index: CommandIndex::FromEnd(exit_codes.len() - i),
+                output: format!("output {}", i + 1),
+                meta: exit_code.map(|code| CommandMeta {
+                    exit_code: Some(code),
+                    ..Default::default()
+                }),
+            })
+            .collect()
+    }
+
+    /// The outputs of the commands selected from records with these exit codes
+    fn select(
+        exit_codes: &[Option<i32>],
+        cmd: Option<i64>,
+        last: Option<usize>,
+    ) -> Result<Vec<String>, WtgError> {
+        let selected = select_from(records(exit_codes), "session.log", cmd, last)?;
+        Ok(selected.into_iter().map(|record| record.output).collect())
+    }
+
+    #[test]
+    fn counts_recent_commands_needed() {
+        assert_eq!(recent_count(Some(-3), None), Some(3));
+        assert_eq!(recent_count(Some(2), None), None);
+        assert_eq!(recent_count(None, Some(4)), Some(4));
+        assert_eq!(recent_count(None, Some(0)), Some(1));
+        assert_eq!(recent_count(None, None), Some(FAILURE_SEARCH_DEPTH));
+    }
+
+    #[test]
+    fn selects_by_index() {
+        let codes = [Some(0), Some(1), Some(0)];
+        assert_eq!(select(&codes, Some(1), None).unwrap(), ["output 1"]);
+        assert_eq!(select(&codes, Some(3), None).unwrap(), ["output 3"]);
+        assert!(matches!(
+            select(&codes, Some(4), None),
+            Err(WtgError::CommandNotFound { cmd: 4, count: 3 })
+        ));
+        assert!(matches!(
+            select(&codes, Some(0), None),
+            Err(WtgError::CommandNotFound { cmd: 0, count: 3 })
+        ));
+    }
+
+    #[test]
+    fn selects_from_the_end() {
+        // the records are the `recent_count` most recent commands, the first is selected
+        let codes = [Some(0), Some(1), Some(0)];
+        assert_eq!(select(&codes[2..], Some(-1), None).unwrap(), ["output 1"]);
+        assert_eq!(select(&codes, Some(-3), None).unwrap(), ["output 1"]);
+        // fewer commands were recorded
+        assert!(matches!(
+            select(&codes, Some(-4), None),
+            Err(WtgError::CommandNotFound { cmd: -4, count: 3 })
+        ));
+    }
+
+    #[test]
+    fn selects_the_last_commands() {
+        let codes = [Some(0), Some(1)];
+        assert_eq!(
+            select(&codes, None, Some(2)).unwrap(),
+            ["output 1", "output 2"]
+        );
+        // as many as were recorded
+        assert_eq!(select(&codes[1..], None, Some(3)).unwrap(), ["output 1"]);
+    }
+
+    #[test]
+    fn selects_the_most_recent_failure_by_default() {
+        let codes = [Some(2), Some(0), Some(1), Some(0), None];
+        assert_eq!(select(&codes, None, None).unwrap(), ["output 3"]);
+        // the most recent command if none is known to have failed
+        assert_eq!(select(&[Some(0), None], None, None).unwrap(), ["output 2"]);
+    }
+
+    #[test]
+    fn rejects_empty_logs() {
+        for (cmd, last) in [
+            (None, None),
+            (Some(1), None),
+            (Some(-1), None),
+            (None, Some(2)),
+        ] {
+            assert!(matches!(
+                select(&[], cmd, last),
+                Err(WtgError::NoCommandRun { logfile }) if logfile == "session.log"
+            ));
+        }
+        // only the running command
+        let log = TempLog::new("empty.log", &format!("{}$ wtg q\r\n", NEW_COMMAND_MSG));
+        assert!(matches!(
+            select_commands(log.path(), None, None),
+            Err(WtgError::NoCommandRun { .. })
+        ));
+    }
+
+    #[test]
+    fn selects_commands_from_the_log() {
+        let content: String = [(1, 0), (2, 2), (3, 0)]
+            .iter()
+            .map(|&(i, code)| text_command(&meta(i, code, false), "out\r\n"))
+            .collect();
+        let log = TempLog::new("select.log", &content);
+        let selected = select_commands(log.path(), None, None).unwrap();
+        assert_eq!(selected[0].index, CommandIndex::FromEnd(2));
+        let selected = select_commands(log.path(), Some(3), None).unwrap();
+        assert_eq!(selected[0].index, CommandIndex::FromStart(3));
+        assert_eq!(selected[0].output, "$ cmd 3\nout\n");
+    }
+}
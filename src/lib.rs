@@ -14,19 +14,39 @@
 //! ```
 //!
 //! ## Environment Variables:
-//! - `WTG_OPENAI_KEY`: Required. The OpenAI API key to use for any queries or chats.
-//! - `WTG_LOG`: Optional for queries and chats. Specifies the absolute (recommended) or relative log file to use for queries and chats. If not specified, `logfile` arg must be provided.
-//! - `WTG_LLM`: Optional. The model to use for the session (default: gpt-4o, also valid: gpt-4o-mini, o3-mini)
+//! - `WTG_OPENAI_KEY`: Required for the `openai` provider with the default base URL. The OpenAI API key to use for any queries or chats.
+//! - `WTG_PROVIDER`: Optional. The LLM provider to use for queries and chats (default: openai)
+//! - `WTG_BASE_URL`: Optional. The base URL of an OpenAI compatible API (default: https://api.openai.com/v1)
+//! - `WTG_LOG`: Optional for queries, chats and `wtg run` outside a session. Specifies the absolute (recommended) or relative log file to use for queries and chats. If not specified, `logfile` arg must be provided.
+//! - `WTG_LLM`: Optional. The model to use for the session (default: gpt-4o). Any model identifier accepted by the provider is valid.
+//! - `WTG_CONTEXT_WINDOW`: Optional. The context window (in tokens) of the model, for models unknown to `wtg`.
 //! - `WTG_PROMPT`: Optional. The default prompt to use for `query` if none is provided by the user.
+//! - `WTG_PROFILE`: Optional. The config file profile to use, see [`config`].
+//! - `WTG_CONFIG`: Optional. The path of the config file.
+//! - `WTG_SESSION`: Set by the WTG session to its process id, used by `wtg pause` and `wtg resume`.
+//! - `WTG_SOCK`: Set by the WTG session to the path of its control socket, see [`control`].
 //!
 //! ## Notes:
-//! - The WTG session uses a heuristic to determine new commands.
-//!   A "new command indicator" is added to the log file on each new command as indicated by a new line.
+//! - For bash (4.4+), zsh and fish, the WTG session installs shell hooks which mark the start and end
+//!   of each command (see [`shell`]). For other shells (or with `--no-shell-integration`) the session
+//!   uses a heuristic to determine new commands: a "new command indicator" is added to the log file
+//!   on each new command as indicated by a new line.
 //! - Similar to `script`, the log file is not automatically cleaned up for visibility after a session.
 //!   Users should manually delete the log when the session is complete
 //!   and the log is not needed
 //!
+pub mod auto;
+pub mod budget;
 pub mod cli;
+pub mod config;
+pub mod control;
+pub mod diagnostics;
 pub mod errors;
+pub mod history;
+pub mod llm;
 pub mod openai;
+pub mod recorder;
+pub mod redact;
+pub mod sanitize;
 pub mod session;
+pub mod shell;
@@ -0,0 +1,331 @@
+//! Provider agnostic utilities for querying an LLM with the log context.
+//!
+//! Each LLM backend implements [`Provider`]. The provider used by queries and chats is
+//! selected via the `--provider` CLI arg, the `WTG_PROVIDER` env var or a config profile.
+
+use std::{
+    env,
+    error::Error,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{
+    LlmArgs, Model, ModelInfo, ProviderKind, DEFAULT_LLM, DEFAULT_PROVIDER, DEFAULT_QUERY,
+};
+use crate::config::{Config, Profile};
+use crate::errors::WtgError;
+use crate::openai::OpenAi;
+
+/// A chat message sent to a provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "system".to_string(),
+            content: content.into(),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "user".to_string(),
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "assistant".to_string(),
+            content: content.into(),
+        }
+    }
+}
+
+/// A command's output, and what is known about the command, to give the model as context
+#[derive(Debug, Clone, Default)]
+pub struct CommandContext {
+    /// The command line
+    pub command: Option<String>,
+    /// How the command finished, e.g. "exit code 2 after 14s"
+    pub status: Option<String>,
+    pub output: String,
+}
+
+impl CommandContext {
+    /// Context of output without any known command (e.g. piped into `wtg`)
+    pub fn from_output(output: String) -> Self {
+        CommandContext {
+            output,
+            ..Default::default()
+        }
+    }
+}
+
+/// The system message which gives the model the log context
+pub fn system_message(contexts: &[CommandContext]) -> ChatMessage {
+    if let [CommandContext {
+        command: None,
+        status: None,
+        output,
+    }] = contexts
+    {
+        return ChatMessage::system(format!(
+            "You are a helpful assistant. The user has run a command and received the following output: {}",
+            output
+        ));
+    }
+    let mut content = String::from(
+        "You are a helpful assistant. The user has run the following in their terminal.",
+    );
+    for context in contexts {
+        content.push_str("\n\n");
+        if let Some(command) = &context.command {
+            content.push_str(&format!("Command: {}\n", command));
+        }
+        if let Some(status) = &context.status {
+            content.push_str(&format!("Status: {}\n", status));
+        }
+        content.push_str(&format!("Output:\n{}", context.output));
+    }
+    ChatMessage::system(content)
+}
+
+/// Token usage of a request, if reported by the provider
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+/// The assembled response of a streamed request
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    /// The assistant text, concatenated from all content deltas
+    pub content: String,
+    /// Why the model stopped generating (e.g. `stop`, `length`), if reported
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+}
+
+/// Receives each content delta as it is streamed from the provider
+pub type DeltaSink<'a> = dyn FnMut(&str) -> io::Result<()> + 'a;
+
+/// Sink which prints content deltas to stdout as they arrive
+pub fn print_delta(delta: &str) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(delta.as_bytes())?;
+    stdout.flush()
+}
+
+/// An LLM backend which can answer a conversation
+pub trait Provider {
+    /// Human readable name of the provider, used in error messages
+    fn name(&self) -> &str;
+
+    /// The URL requests are sent to
+    fn endpoint(&self) -> String;
+
+    /// Send `messages` to `model`, passing each response content delta to `sink` as it is streamed.
+    /// Returns the assembled response once the stream has finished.
+    fn stream_chat(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        sink: &mut DeltaSink,
+    ) -> Result<Completion, Box<dyn Error>>;
+}
+
+/// LLM settings resolved from, in order of precedence, the CLI args, env vars,
+/// the selected config profile and the defaults
+#[derive(Debug, Clone)]
+pub struct LlmSettings {
+    pub provider: ProviderKind,
+    pub model: Model,
+    pub model_info: ModelInfo,
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// The default prompt for queries
+    pub prompt: String,
+}
+
+impl LlmSettings {
+    pub fn resolve(args: &LlmArgs) -> Result<Self, WtgError> {
+        Self::resolve_with_key(args, true)
+    }
+
+    /// Resolve the settings without looking up the API key, which may run the profile's
+    /// `key_command` (e.g. for a dry run, which sends no request)
+    pub fn resolve_without_key(args: &LlmArgs) -> Result<Self, WtgError> {
+        Self::resolve_with_key(args, false)
+    }
+
+    fn resolve_with_key(args: &LlmArgs, with_key: bool) -> Result<Self, WtgError> {
+        let profile = Config::load()?.profile(args.profile.as_deref())?;
+
+        let provider = match args.provider {
+            Some(provider) => provider,
+            None => match env::var("WTG_PROVIDER").ok().or(profile.provider.clone()) {
+                Some(provider) => {
+                    ProviderKind::from_str(&provider).map_err(WtgError::ProviderError)?
+                }
+                None => DEFAULT_PROVIDER,
+            },
+        };
+        let model = match &args.model {
+            Some(model) => model.clone(),
+            None => match env::var("WTG_LLM").ok().or(profile.model.clone()) {
+                Some(model) => Model::from_str(&model).map_err(WtgError::ProviderError)?,
+                None => DEFAULT_LLM,
+            },
+        };
+        let mut model_info = model.info();
+        if let Some(context_window) = args.context_window {
+            model_info.context_window = Some(context_window);
+        } else if let Ok(context_window) = env::var("WTG_CONTEXT_WINDOW") {
+            model_info.context_window = Some(context_window.parse().map_err(|_| {
+                WtgError::ProviderError(format!("Invalid WTG_CONTEXT_WINDOW: {}", context_window))
+            })?);
+        } else if profile.context_window.is_some() {
+            model_info.context_window = profile.context_window;
+        }
+        let base_url = args
+            .base_url
+            .clone()
+            .or_else(|| env::var("WTG_BASE_URL").ok())
+            .or_else(|| profile.base_url.clone());
+        let api_key = resolve_api_key(&profile, env::var(provider.api_key_env()).ok(), with_key)?;
+        let prompt = env::var("WTG_PROMPT")
+            .ok()
+            .or_else(|| profile.prompt.clone())
+            .unwrap_or_else(|| DEFAULT_QUERY.to_string());
+
+        Ok(LlmSettings {
+            provider,
+            model,
+            model_info,
+            base_url,
+            api_key,
+            prompt,
+        })
+    }
+}
+
+/// The API key to send. The profile's `key_command` is used over the provider's key env var
+/// (`env_key`), and only run `with_key`. The env var is not sent to a `base_url` set by the
+/// profile (e.g. a third party server) unless the profile sets `use_env_key`.
+fn resolve_api_key(
+    profile: &Profile,
+    env_key: Option<String>,
+    with_key: bool,
+) -> Result<Option<String>, WtgError> {
+    if profile.key_command.is_some() {
+        return if with_key {
+            profile.api_key()
+        } else {
+            Ok(None)
+        };
+    }
+    if profile.base_url.is_some() && !profile.use_env_key {
+        return Ok(None);
+    }
+    Ok(env_key)
+}
+
+/// Build the provider selected by the resolved settings
+pub fn build_provider(settings: &LlmSettings) -> Result<Box<dyn Provider>, WtgError> {
+    match settings.provider {
+        ProviderKind::OpenAi => Ok(Box::new(OpenAi::new(
+            settings.base_url.clone(),
+            settings.api_key.clone(),
+        )?)),
+    }
+}
+
+/// Build the provider for a dry run. Only its name and endpoint are used, so a missing API key
+/// is not an error.
+pub fn build_dry_run_provider(settings: &LlmSettings) -> Box<dyn Provider> {
+    match settings.provider {
+        ProviderKind::OpenAi => Box::new(OpenAi::unchecked(
+            settings.base_url.clone(),
+            settings.api_key.clone(),
+        )),
+    }
+}
+
+/// The messages of a query: the commands as context and the user prompt
+pub fn query_messages(contexts: &[CommandContext], prompt: &str) -> Vec<ChatMessage> {
+    vec![system_message(contexts), ChatMessage::user(prompt)]
+}
+
+/// Query the provider with the commands as context and a user prompt, streaming the response to `sink`
+pub fn query_llm(
+    provider: &dyn Provider,
+    contexts: &[CommandContext],
+    prompt: &str,
+    model: &Model,
+    sink: &mut DeltaSink,
+) -> Result<Completion, Box<dyn Error>> {
+    let messages = query_messages(contexts, prompt);
+    provider.stream_chat(&model.to_string(), &messages, sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env_key() -> Option<String> {
+        Some("env-key".to_string())
+    }
+
+    #[test]
+    fn uses_the_env_key_without_a_profile() {
+        let key = resolve_api_key(&Profile::default(), env_key(), true).unwrap();
+        assert_eq!(key.as_deref(), Some("env-key"));
+        let key = resolve_api_key(&Profile::default(), None, true).unwrap();
+        assert_eq!(key, None);
+    }
+
+    #[test]
+    fn prefers_the_key_command() {
+        let profile = Profile {
+            key_command: Some("echo profile-key".to_string()),
+            ..Default::default()
+        };
+        let key = resolve_api_key(&profile, env_key(), true).unwrap();
+        assert_eq!(key.as_deref(), Some("profile-key"));
+        // not run for a dry run
+        let profile = Profile {
+            key_command: Some("exit 1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_api_key(&profile, env_key(), false).unwrap(), None);
+        assert!(matches!(
+            resolve_api_key(&profile, env_key(), true),
+            Err(WtgError::KeyCommandFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn keeps_the_env_key_from_the_profile_base_url() {
+        let profile = Profile {
+            base_url: Some("https://llm.example.com/v1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_api_key(&profile, env_key(), true).unwrap(), None);
+        let profile = Profile {
+            use_env_key: true,
+            ..profile
+        };
+        let key = resolve_api_key(&profile, env_key(), true).unwrap();
+        assert_eq!(key.as_deref(), Some("env-key"));
+    }
+}
@@ -1,19 +1,26 @@
 use clap::Parser;
 use wtg::{
     cli::{Args, Commands},
-    session::{run_chat, run_query, run_session},
+    session::{run_chat, run_command, run_history, run_pause, run_query, run_session},
 };
 
 fn main() {
     let args = Args::parse();
     let res = match args.command {
-        Commands::Start { logfile } => run_session(&logfile),
+        Commands::Start { session, llm } => run_session(&session, &llm),
         Commands::Query {
-            logfile,
+            context,
             prompt,
-            model,
-        } => run_query(logfile, prompt, model),
-        Commands::Chat { logfile, model } => run_chat(logfile, model),
+            llm,
+        } => run_query(context, prompt, llm),
+        Commands::Chat { context, llm } => run_chat(context, llm),
+        Commands::History { logfile } => run_history(logfile),
+        Commands::Run { run, llm } => match run_command(&run, llm) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => Err(e),
+        },
+        Commands::Pause => run_pause(true),
+        Commands::Resume => run_pause(false),
     };
     res.unwrap_or_else(|e| {
         eprintln!("{}", e);
@@ -3,28 +3,34 @@
 //! For specific details on request/response schemas, see the [OpenAI API chat completionsdocs](https://platform.openai.com/docs/api-reference/chat/create).
 
 use std::{
-    env,
-    io::{BufRead, BufReader, Write},
-    str::FromStr,
+    error::Error,
+    io::{BufRead, BufReader},
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::cli::{Model, DEFAULT_LLM, DEFAULT_QUERY};
+use crate::errors::WtgError;
+use crate::llm::{ChatMessage, Completion, DeltaSink, Provider, Usage};
 
-/// A `chat/completions` `messages` item
-#[derive(Serialize, Deserialize)]
-pub struct ChatMessage {
-    pub role: String,
-    pub content: String,
-}
+/// The default base URL of the OpenAI API
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
 
 /// A `chat/completions` request body
-#[derive(Serialize, Deserialize)]
-pub struct ChatRequest {
-    pub model: String,
-    pub messages: Vec<ChatMessage>,
+#[derive(Serialize)]
+pub struct ChatRequest<'a> {
+    pub model: &'a str,
+    pub messages: &'a [ChatMessage],
     pub stream: bool,
+    /// Only sent to the OpenAI API, since some compatible servers reject unknown fields
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+/// A `chat/completions` request `stream_options`
+#[derive(Serialize)]
+pub struct StreamOptions {
+    /// Request a final chunk with the token usage of the request
+    pub include_usage: bool,
 }
 
 /// A `chat/completions` streaming response delta
@@ -37,95 +43,142 @@ pub struct ChatDelta {
 #[derive(Deserialize)]
 pub struct ChatStreamChoice {
     pub delta: ChatDelta,
+    pub finish_reason: Option<String>,
 }
 
 /// A `chat/completions` streaming response
 #[derive(Deserialize)]
 pub struct ChatStreamResponse {
+    /// Empty for the final usage chunk
+    #[serde(default)]
     pub choices: Vec<ChatStreamChoice>,
+    pub usage: Option<Usage>,
+}
+
+/// The OpenAI chat completions provider. Also serves any OpenAI compatible
+/// endpoint (llama.cpp server, vLLM, LM Studio, proxies) via a custom base URL.
+pub struct OpenAi {
+    base_url: String,
+    api_key: Option<String>,
 }
 
-/// Query the OpenAI API via the chat completions endpoint
-pub fn query_chatgpt(
-    context: &str,
-    prompt: Option<&str>,
-    model: Option<Model>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let openai_key = env::var("WTG_OPENAI_KEY").expect("WTG_OPENAI_KEY not set");
-    let default_model = env::var("WTG_LLM").unwrap_or_else(|_| DEFAULT_LLM.to_string());
-    let model = model
-        .map(|m| m.to_string())
-        .unwrap_or_else(|| default_model);
-    let default_prompt = env::var("WTG_PROMPT").unwrap_or_else(|_| DEFAULT_QUERY.to_string());
-    let prompt = prompt.unwrap_or_else(|| &default_prompt);
-
-    // Validate the user model is supported
-    if Model::from_str(&model).is_err() {
-        return Err(format!(
-            "Model {} is not a supported model, double check your WTG_LLM env var. Only {} are supported.",
+impl OpenAi {
+    /// Create a provider for the base URL (default: the OpenAI API).
+    /// The API key is only required for the default OpenAI base URL, since local servers often have no auth.
+    pub fn new(base_url: Option<String>, api_key: Option<String>) -> Result<Self, WtgError> {
+        let provider = Self::unchecked(base_url, api_key);
+        if provider.api_key.is_none() && provider.base_url == DEFAULT_BASE_URL {
+            return Err(WtgError::ProviderError(
+                "WTG_OPENAI_KEY not set (or set a `key_command` in the config profile)".to_string(),
+            ));
+        }
+        Ok(provider)
+    }
+
+    /// Create a provider without checking that the API key is set, e.g. to show its endpoint
+    pub fn unchecked(base_url: Option<String>, api_key: Option<String>) -> Self {
+        OpenAi {
+            base_url: base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            api_key,
+        }
+    }
+
+    /// The `chat/completions` endpoint under the base URL
+    pub fn chat_completions_url(&self) -> String {
+        format!("{}/chat/completions", self.base_url.trim_end_matches('/'))
+    }
+
+    /// The streaming request body. The token usage is only requested from the OpenAI API.
+    fn chat_request<'a>(&self, model: &'a str, messages: &'a [ChatMessage]) -> ChatRequest<'a> {
+        let is_openai = self.base_url.trim_end_matches('/') == DEFAULT_BASE_URL;
+        ChatRequest {
             model,
-            Model::all_models().join(", ")
-        )
-        .into());
+            messages,
+            stream: true, // Request a streaming response.
+            stream_options: is_openai.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        }
     }
+}
 
-    // Useful for debugging the model inputs
-    // println!("Context: {}", context);
-    // println!("User Prompt: {}", prompt);
-
-    let client = reqwest::blocking::Client::new();
-    let url = "https://api.openai.com/v1/chat/completions";
-
-    let system_msg = ChatMessage {
-        role: "system".to_string(),
-        content: format!(
-            "You are a helpful assistant. The user has run a command and received the following output: {}",
-            context
-        ),
-    };
-    let user_msg = ChatMessage {
-        role: "user".to_string(),
-        content: prompt.to_string(),
-    };
-    let req_body = ChatRequest {
-        model: model.to_string(),
-        messages: vec![system_msg, user_msg],
-        stream: true, // Request a streaming response.
-    };
-
-    let response = client
-        .post(url)
-        .bearer_auth(openai_key)
-        .json(&req_body)
-        .send()?
-        .error_for_status()?;
-
-    let mut reader = BufReader::new(response);
-    let mut line = String::new();
-    let mut complete_response = String::new();
-
-    while reader.read_line(&mut line)? != 0 {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            line.clear();
-            continue;
+impl Provider for OpenAi {
+    fn name(&self) -> &str {
+        "OpenAI"
+    }
+
+    fn endpoint(&self) -> String {
+        self.chat_completions_url()
+    }
+
+    fn stream_chat(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        sink: &mut DeltaSink,
+    ) -> Result<Completion, Box<dyn Error>> {
+        let client = reqwest::blocking::Client::new();
+        let url = self.chat_completions_url();
+
+        let req_body = self.chat_request(model, messages);
+
+        let mut request = client.post(url).json(&req_body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
         }
-        if trimmed.starts_with("data: ") {
-            let data = trimmed.trim_start_matches("data: ").trim();
-            if data == "[DONE]" {
-                break;
-            }
-            let parsed: ChatStreamResponse = serde_json::from_str(data)?;
-            if let Some(choice) = parsed.choices.first() {
-                if let Some(content) = &choice.delta.content {
-                    print!("{}", content);
-                    std::io::stdout().flush()?;
+        let response = request.send()?.error_for_status()?;
+
+        let mut reader = BufReader::new(response);
+        let mut line = String::new();
+        let mut completion = Completion::default();
+
+        // Server sent events, each `data: ` line holds one JSON chunk
+        while reader.read_line(&mut line)? != 0 {
+            let trimmed = line.trim();
+            if let Some(data) = trimmed.strip_prefix("data: ") {
+                let data = data.trim();
+                if data == "[DONE]" {
+                    break;
+                }
+                let parsed: ChatStreamResponse = serde_json::from_str(data)?;
+                if let Some(choice) = parsed.choices.first() {
+                    if let Some(content) = &choice.delta.content {
+                        sink(content)?;
+                        completion.content.push_str(content);
+                    }
+                    if choice.finish_reason.is_some() {
+                        completion.finish_reason = choice.finish_reason.clone();
+                    }
+                }
+                if parsed.usage.is_some() {
+                    completion.usage = parsed.usage;
                 }
             }
+            line.clear();
         }
-        complete_response.push_str(&line);
-        line.clear();
+        Ok(completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_json(base_url: Option<&str>) -> serde_json::Value {
+        let provider = OpenAi::unchecked(base_url.map(str::to_string), None);
+        serde_json::to_value(provider.chat_request("gpt-4o", &[])).unwrap()
+    }
+
+    #[test]
+    fn requests_usage_only_from_openai() {
+        let include_usage = serde_json::json!({ "include_usage": true });
+        assert_eq!(request_json(None)["stream_options"], include_usage);
+        assert_eq!(
+            request_json(Some("https://api.openai.com/v1/"))["stream_options"],
+            include_usage
+        );
+        let local = request_json(Some("http://localhost:8080/v1"));
+        assert_eq!(local["stream"], true);
+        assert!(local.get("stream_options").is_none());
     }
-    println!();
-    Ok(complete_response)
 }
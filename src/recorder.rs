@@ -0,0 +1,963 @@
+//! Records the pty output of a WTG session to the log files.
+//!
+//! With shell integration (see [`crate::shell`]) command boundaries come from the shell's
+//! OSC 133 markers. Until the shell emits its first marker (or for shells without integration),
+//! a new command is assumed to start whenever Enter is pressed. With shell integration, the
+//! command line, working directory, exit code and timing are also recorded when the command
+//! finishes.
+//!
+//! The session is recorded in either or both log formats (see [`LogFormat`]):
+//! - `text`: output is written as it arrives, with a [`NEW_COMMAND_MSG`] delimiter when each
+//!   command starts and the [`CommandMeta`] line when it finishes
+//! - `jsonl`: each command's output is buffered and written as one [`LogRecord`] when it finishes
+//!
+//! The size of long sessions is bounded by [`LogLimits`].
+//!
+//! Recording is paused by `wtg pause` and resumed by `wtg resume`. In between, no output or
+//! commands are recorded, only a [`PAUSED_MSG`] placeholder in the output of `wtg pause`.
+//!
+//! The most recent commands are also kept in memory, for clients of the session's control
+//! socket (see [`crate::control`]).
+//!
+//! `wtg` invocations (e.g. earlier queries) are recorded with the `wtg` flag of their
+//! [`CommandMeta`], and are not kept in memory.
+//!
+//! The output of full-screen apps on the alternate screen (e.g. vim, less, htop) is mostly
+//! redraws, so it is replaced with a placeholder such as `[full-screen app: vim, 3m12s]`.
+
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cli::{LogFormat, SessionArgs, NEW_COMMAND_MSG};
+use crate::config::Config;
+use crate::errors::WtgError;
+use crate::history::{format_duration, user_command_starts, CommandMeta, LogRecord};
+use crate::shell::{OscParser, PtyChunk, ShellEvent};
+
+/// Recorded in place of the output while recording is paused
+pub const PAUSED_MSG: &str = "\r\n[wtg: recording paused]\r\n";
+
+/// The most commands kept in memory
+const RECENT_COMMANDS: usize = 100;
+/// The most output bytes kept in memory, older commands are dropped first
+const MAX_RECENT_SIZE: usize = 64 * 1024 * 1024;
+/// The most output of a running command kept in memory, half from the start and half from the
+/// end, whatever the log options. Also bounds the output of `jsonl` records.
+const MAX_COMMAND_MEMORY: usize = 8 * 1024 * 1024;
+
+/// Prompt output held back beyond this size is written to the log as is. Guards against
+/// a shell which marks prompts but never marks commands.
+const MAX_PENDING_PROMPT: usize = 64 * 1024;
+
+/// Limits on the size of the session logs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogLimits {
+    /// Bytes of each command's output to keep, half from the start and half from the end
+    pub max_output_size: Option<u64>,
+    /// Size at which a log is moved to `<log>.1` and a new log is started
+    pub max_log_size: Option<u64>,
+    /// The number of most recent commands to keep in a log
+    pub keep_commands: Option<u64>,
+}
+
+impl LogLimits {
+    /// Resolve the limits from the CLI args, then the `[session]` table of the config file
+    pub fn resolve(args: &SessionArgs) -> Result<Self, WtgError> {
+        let config = Config::load()?.session;
+        Ok(LogLimits {
+            max_output_size: args.max_output_size.or(config.max_output_size).map(|s| s.0),
+            max_log_size: args.max_log_size.or(config.max_log_size).map(|s| s.0),
+            keep_commands: args.keep_commands.or(config.keep_commands),
+        })
+    }
+    /// The limits in the `[session]` table of the config file, for logs written by `wtg run`
+    pub fn from_config() -> Result<Self, WtgError> {
+        let config = Config::load()?.session;
+        Ok(LogLimits {
+            max_output_size: config.max_output_size.map(|s| s.0),
+            max_log_size: config.max_log_size.map(|s| s.0),
+            keep_commands: config.keep_commands,
+        })
+    }
+}
+
+/// A log file which is appended to, and rotated or pruned when it exceeds the [`LogLimits`]
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    format: LogFormat,
+    /// The current size in bytes
+    size: u64,
+    /// The number of commands in the log (without `wtg` invocations), only counted if old
+    /// commands are pruned
+    commands: u64,
+}
+
+impl LogFile {
+    /// Open a log for appending, creating it if needed
+    pub fn open(path: &str, format: LogFormat, limits: &LogLimits) -> Result<Self, WtgError> {
+        let open_error = |_| WtgError::LogFileOpenError {
+            logfile: path.to_string(),
+        };
+        let path = PathBuf::from(path);
+        let file = open_append(&path).map_err(open_error)?;
+        let size = file.metadata().map_err(open_error)?.len();
+        let commands = match limits.keep_commands {
+            Some(_) => {
+                user_command_starts(&fs::read(&path).map_err(open_error)?, format).len() as u64
+            }
+            None => 0,
+        };
+        Ok(LogFile {
+            path,
+            file,
+            format,
+            size,
+            commands,
+        })
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Called between commands to rotate or prune the log. Returns whether a new log was started.
+    fn enforce_limits(&mut self, limits: &LogLimits) -> io::Result<bool> {
+        if limits.max_log_size.is_some_and(|max| self.size > max) {
+            self.rotate()?;
+            return Ok(true);
+        }
+        if let Some(keep) = limits.keep_commands {
+            // allow some slack, so the log is not rewritten after every command
+            if self.commands > keep + keep / 10 {
+                self.prune(keep)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Move the log to `<log>.1`, replacing any previous one, and start a new log
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::rename(&self.path, with_suffix(&self.path, ".1"))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.commands = 0;
+        Ok(())
+    }
+
+    /// Rewrite the log with only the `keep` most recent commands. `wtg` invocations are not
+    /// counted, those older than the kept commands are dropped.
+    fn prune(&mut self, keep: u64) -> io::Result<()> {
+        let content = fs::read(&self.path)?;
+        let starts = user_command_starts(&content, self.format);
+        let Some(&start) = starts
+            .len()
+            .checked_sub(keep as usize)
+            .and_then(|first| starts.get(first))
+        else {
+            self.commands = starts.len() as u64;
+            return Ok(());
+        };
+        let tmp = with_suffix(&self.path, ".tmp");
+        fs::write(&tmp, &content[start..])?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = open_append(&self.path)?;
+        self.size = (content.len() - start) as u64;
+        self.commands = keep;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// The path with `suffix` appended to the file name, e.g. `session.log.1`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// The program run by a command line: its first word which is not an env var assignment,
+/// without its directory
+pub fn program_name(command: &str) -> Option<&str> {
+    let program = command
+        .split_whitespace()
+        .find(|word| !word.contains('='))?;
+    Some(program.rsplit('/').next().unwrap_or(program))
+}
+
+/// A full-screen app showing the alternate screen
+struct FullScreenApp {
+    /// The program name, taken from the command line
+    name: Option<String>,
+    start: Instant,
+}
+
+impl FullScreenApp {
+    fn new(command: Option<&str>) -> Self {
+        let name = command.and_then(program_name).map(str::to_string);
+        FullScreenApp {
+            name,
+            start: Instant::now(),
+        }
+    }
+
+    /// Recorded in place of the app's output
+    fn placeholder(&self) -> String {
+        let duration = format_duration(self.start.elapsed());
+        match &self.name {
+            Some(name) => format!("\r\n[full-screen app: {}, {}]\r\n", name, duration),
+            None => format!("\r\n[full-screen app, {}]\r\n", duration),
+        }
+    }
+}
+
+/// A command which has started but not finished yet
+struct RunningCommand {
+    command: Option<String>,
+    cwd: Option<String>,
+    started_at: SystemTime,
+    start: Instant,
+    /// The output so far, up to half of [`MAX_COMMAND_MEMORY`]
+    output: Vec<u8>,
+    /// The most recent output past `output`, up to half of [`MAX_COMMAND_MEMORY`]
+    output_tail: VecDeque<u8>,
+    /// Bytes of output dropped between `output` and `output_tail`
+    output_omitted: u64,
+    /// Bytes of output seen so far
+    size: u64,
+    /// The most recent output past the start kept by `max_output_size`, held until the command
+    /// finishes
+    tail: VecDeque<u8>,
+    /// Bytes of output dropped between the start and the tail
+    omitted: u64,
+    /// Set if the command is a `wtg` invocation
+    wtg: bool,
+}
+
+impl RunningCommand {
+    fn new(command: Option<String>, cwd: Option<String>, prompt: Vec<u8>) -> Self {
+        RunningCommand {
+            command,
+            cwd,
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            output: prompt,
+            output_tail: VecDeque::new(),
+            output_omitted: 0,
+            size: 0,
+            tail: VecDeque::new(),
+            omitted: 0,
+            wtg: false,
+        }
+    }
+
+    /// Account for the next output, moving any beyond the start kept by `max_output_size` to
+    /// the tail. Returns how many leading bytes of `output` should be recorded now.
+    fn cap_output(&mut self, output: &[u8], max_output_size: Option<u64>) -> usize {
+        let len = output.len() as u64;
+        let Some(max) = max_output_size else {
+            self.size += len;
+            return output.len();
+        };
+        let head_len = (max / 2).saturating_sub(self.size).min(len) as usize;
+        self.size += len;
+        self.tail.extend(&output[head_len..]);
+        let excess = self.tail.len().saturating_sub((max - max / 2) as usize);
+        self.tail.drain(..excess);
+        self.omitted += excess as u64;
+        head_len
+    }
+
+    /// Keep output in memory, moving any beyond the start kept by [`MAX_COMMAND_MEMORY`] to the
+    /// output tail
+    fn keep_output(&mut self, output: &[u8]) {
+        let half = MAX_COMMAND_MEMORY / 2;
+        let head_len = half.saturating_sub(self.output.len()).min(output.len());
+        self.output.extend_from_slice(&output[..head_len]);
+        self.output_tail.extend(&output[head_len..]);
+        let excess = self.output_tail.len().saturating_sub(half);
+        self.output_tail.drain(..excess);
+        self.output_omitted += excess as u64;
+    }
+
+    /// The output kept in memory, with a notice in place of the omitted output
+    fn take_output(&mut self) -> Vec<u8> {
+        let mut output = std::mem::take(&mut self.output);
+        if self.output_omitted > 0 {
+            output.extend(format!("\r\n[wtg: {} bytes omitted]\r\n", self.output_omitted).bytes());
+            self.output_omitted = 0;
+        }
+        output.extend(self.output_tail.drain(..));
+        output
+    }
+
+    /// Remove the last line of the output kept in memory and return it, leaving the rest
+    fn split_last_line(&mut self) -> Vec<u8> {
+        if self.output_tail.is_empty() {
+            return split_last_line(&mut self.output);
+        }
+        let tail = self.output_tail.make_contiguous();
+        let line_start = tail
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        self.output_tail.split_off(line_start).into()
+    }
+
+    /// Take the tail, preceded by a notice if output was omitted
+    fn take_tail(&mut self) -> Vec<u8> {
+        let mut tail = Vec::new();
+        if self.omitted > 0 {
+            tail.extend(format!("\r\n[wtg: {} bytes omitted]\r\n", self.omitted).bytes());
+            self.omitted = 0;
+        }
+        tail.extend(self.tail.drain(..));
+        tail
+    }
+}
+
+/// Writes the session's pty output to the logs, delimiting each command
+pub struct Recorder {
+    text_log: Option<LogFile>,
+    jsonl_log: Option<LogFile>,
+    limits: LogLimits,
+    parser: OscParser,
+    /// Set once the shell emits a shell integration marker
+    integrated: bool,
+    /// Set by `wtg pause`, no output or commands are recorded until `wtg resume`
+    paused: bool,
+    /// Set while a full-screen app shows the alternate screen, its output is not recorded
+    full_screen: Option<FullScreenApp>,
+    /// Output since the last prompt started. Held back until the command starts so the
+    /// prompt line is logged with its command, or discarded if no command is run.
+    pending_prompt: Option<Vec<u8>>,
+    /// Whether the last byte written to the text log was a newline
+    at_line_start: bool,
+    /// The working directory last reported by the shell
+    cwd: Option<String>,
+    /// The running command, if its start is known
+    running: Option<RunningCommand>,
+    /// Without shell integration, the last line of output before the first command. It holds
+    /// the prompt and typed command once Enter is pressed.
+    unattributed_line: Vec<u8>,
+    /// The most recent finished commands, oldest first
+    recent: VecDeque<LogRecord>,
+    /// Output bytes in `recent`
+    recent_size: usize,
+    /// Set when a command finishes with a non-zero exit code, until [`Recorder::take_failed`]
+    failed: bool,
+}
+
+impl Recorder {
+    pub fn new(text_log: Option<LogFile>, jsonl_log: Option<LogFile>, limits: LogLimits) -> Self {
+        Recorder {
+            text_log,
+            jsonl_log,
+            limits,
+            parser: OscParser::default(),
+            integrated: false,
+            paused: false,
+            full_screen: None,
+            pending_prompt: None,
+            at_line_start: true,
+            cwd: None,
+            running: None,
+            unattributed_line: Vec::new(),
+            recent: VecDeque::new(),
+            recent_size: 0,
+            failed: false,
+        }
+    }
+
+    /// The `count` most recent finished commands (or all kept in memory), oldest first
+    pub fn recent_commands(&self, count: Option<usize>) -> impl Iterator<Item = &LogRecord> {
+        let count = count.unwrap_or(self.recent.len());
+        self.recent
+            .iter()
+            .skip(self.recent.len().saturating_sub(count))
+    }
+
+    /// The command which failed since the last call, if it is the most recent command. Only
+    /// commands with a known non-zero exit code count, which needs the shell integration.
+    pub fn take_failed(&mut self) -> Option<LogRecord> {
+        if !std::mem::take(&mut self.failed) {
+            return None;
+        }
+        self.recent.back().cloned()
+    }
+
+    /// Pause or resume recording. When paused, a placeholder is left in the output of the
+    /// running command.
+    pub fn set_paused(&mut self, paused: bool) -> io::Result<()> {
+        if paused && !self.paused {
+            self.record_output(PAUSED_MSG.as_bytes())?;
+        }
+        self.paused = paused;
+        self.flush()
+    }
+
+    /// Finish the running command's output so far as its own command, and continue recording
+    /// the rest as a new command named `label` (by default the same command line). Returns
+    /// `false` if no command is running.
+    pub fn mark(&mut self, label: Option<String>) -> io::Result<bool> {
+        self.split_command(label, None)
+    }
+
+    /// Finish the running command with `exit_code` and record the rest of its output as a new
+    /// command named `label`. Returns `false` if no command is running.
+    fn split_command(&mut self, label: Option<String>, exit_code: Option<i32>) -> io::Result<bool> {
+        let Some(running) = &self.running else {
+            return Ok(false);
+        };
+        if self.paused {
+            return Ok(false);
+        }
+        let command = label.or_else(|| running.command.clone());
+        let wtg = running.wtg;
+        self.finish_command(exit_code)?;
+        self.start_text_line()?;
+        self.start_text_command()?;
+        let mut running = RunningCommand::new(command, self.cwd.clone(), Vec::new());
+        running.wtg = wtg;
+        self.running = Some(running);
+        self.flush()?;
+        Ok(true)
+    }
+
+    /// Record a command run by `wtg run` rather than typed in a shell. A `$ <command>` prompt
+    /// line is recorded before its output, like a shell's prompt.
+    pub fn start_command(&mut self, command: String) -> io::Result<()> {
+        // the command runs in wtg's working directory
+        self.cwd = std::env::current_dir()
+            .ok()
+            .map(|cwd| cwd.to_string_lossy().into_owned());
+        self.on_event(ShellEvent::PromptStart)?;
+        self.record_output(format!("$ {}\r\n", command).as_bytes())?;
+        self.on_event(ShellEvent::CommandStart {
+            command: Some(command),
+        })?;
+        self.flush()
+    }
+
+    /// Record the command started by [`Recorder::start_command`] as finished
+    pub fn end_command(&mut self, exit_code: i32) -> io::Result<()> {
+        self.on_event(ShellEvent::CommandEnd {
+            exit_code: Some(exit_code),
+        })?;
+        self.flush()
+    }
+
+    /// Called when the user presses Enter, before the input is forwarded to the pty
+    pub fn on_enter(&mut self) -> io::Result<()> {
+        // Enter in a full-screen app does not run a command
+        if self.integrated || self.paused || self.full_screen.is_some() {
+            return Ok(());
+        }
+        self.record_tail()?;
+        // the prompt and typed command were output before Enter, so they belong to the new command
+        let prompt = match &mut self.running {
+            Some(running) => running.split_last_line(),
+            None => std::mem::take(&mut self.unattributed_line),
+        };
+        // finished first, so the log is only pruned between finished commands
+        self.finish_command(None)?;
+        self.start_text_command()?;
+        self.running = Some(RunningCommand::new(None, self.cwd.clone(), prompt));
+        self.flush()
+    }
+
+    /// Called with each read of pty output
+    pub fn on_output(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for chunk in self.parser.feed(bytes) {
+            match chunk {
+                PtyChunk::Output(output) => self.record_output(&output)?,
+                PtyChunk::Event(ShellEvent::Pause) => self.set_paused(true)?,
+                PtyChunk::Event(ShellEvent::Resume) => self.set_paused(false)?,
+                PtyChunk::Event(ShellEvent::WtgCommand) => self.tag_wtg_command()?,
+                PtyChunk::Event(ShellEvent::Mark { exit_code }) => {
+                    self.split_command(None, exit_code)?;
+                }
+                PtyChunk::Event(ShellEvent::AlternateScreen { active: true }) => {
+                    if self.full_screen.is_none() {
+                        let command = self.running.as_ref().and_then(|r| r.command.as_deref());
+                        self.full_screen = Some(FullScreenApp::new(command));
+                    }
+                }
+                PtyChunk::Event(ShellEvent::AlternateScreen { active: false }) => {
+                    self.end_full_screen()?
+                }
+                PtyChunk::Event(event) => self.on_event(event)?,
+            }
+        }
+        self.flush()
+    }
+
+    /// Flag the running command as a `wtg` invocation
+    fn tag_wtg_command(&mut self) -> io::Result<()> {
+        let Some(running) = &mut self.running else {
+            return Ok(());
+        };
+        running.wtg = true;
+        // without shell integration the text log gets no metadata when the command finishes,
+        // so the flag is written now
+        if !self.integrated {
+            let meta = CommandMeta {
+                started_at: unix_secs(running.started_at),
+                wtg: true,
+                ..Default::default()
+            };
+            self.start_text_line()?;
+            self.write_text(meta.to_log_line().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Record the placeholder of the full-screen app, if one is showing
+    fn end_full_screen(&mut self) -> io::Result<()> {
+        match self.full_screen.take() {
+            Some(app) => self.record_output(app.placeholder().as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    fn on_event(&mut self, event: ShellEvent) -> io::Result<()> {
+        // the app exited (or was suspended) without switching back to the main screen
+        self.end_full_screen()?;
+        if !self.integrated {
+            // a command started from the Enter key before the shell emitted its first marker,
+            // its exit code is unknown
+            self.finish_command(None)?;
+            self.integrated = true;
+        }
+        match event {
+            // a prompt without a command (e.g. an empty line) is not logged
+            ShellEvent::PromptStart if !self.paused => self.pending_prompt = Some(Vec::new()),
+            ShellEvent::PromptStart
+            | ShellEvent::Pause
+            | ShellEvent::Resume
+            | ShellEvent::WtgCommand
+            | ShellEvent::Mark { .. }
+            | ShellEvent::AlternateScreen { .. } => {}
+            ShellEvent::CommandStart { command } => {
+                let prompt = self.pending_prompt.take().unwrap_or_default();
+                // the previous command's end was not marked
+                self.finish_command(None)?;
+                if self.paused {
+                    return Ok(());
+                }
+                // the delimiter starts a new line, so the log parser does not attribute the
+                // end of the previous command's output to this command
+                self.start_text_line()?;
+                self.start_text_command()?;
+                self.write_text(&prompt)?;
+                self.running = Some(RunningCommand::new(command, self.cwd.clone(), prompt));
+            }
+            ShellEvent::CommandEnd { exit_code } => {
+                // an end without a start is a prompt without a command (e.g. an empty line)
+                self.finish_command(exit_code)?;
+            }
+            ShellEvent::WorkingDirectory { path } => self.cwd = Some(path),
+        }
+        Ok(())
+    }
+
+    /// Write the text log delimiter for a new command, first rotating or pruning the log
+    fn start_text_command(&mut self) -> io::Result<()> {
+        if let Some(log) = &mut self.text_log {
+            if log.enforce_limits(&self.limits)? {
+                self.at_line_start = true;
+            }
+        }
+        self.write_text(NEW_COMMAND_MSG.as_bytes())
+    }
+
+    /// Record the running command (if any) as finished
+    fn finish_command(&mut self, exit_code: Option<i32>) -> io::Result<()> {
+        self.record_tail()?;
+        let Some(mut running) = self.running.take() else {
+            return Ok(());
+        };
+        let output = running.take_output();
+        let meta = CommandMeta {
+            command: running.command,
+            cwd: running.cwd,
+            exit_code,
+            started_at: unix_secs(running.started_at),
+            duration_ms: running.start.elapsed().as_millis() as u64,
+            wtg: running.wtg,
+        };
+        // without shell integration, the text log keeps only the delimiters
+        if self.integrated {
+            self.start_text_line()?;
+            self.write_text(meta.to_log_line().as_bytes())?;
+        }
+        // `wtg` invocations are not counted as kept commands, unless the text log cannot tell them
+        // apart without their metadata
+        if let Some(log) = &mut self.text_log {
+            if !(meta.wtg && self.integrated) {
+                log.commands += 1;
+            }
+        }
+        let record = LogRecord {
+            meta,
+            output: String::from_utf8_lossy(&output).into_owned(),
+        };
+        if let Some(log) = &mut self.jsonl_log {
+            log.write_all(record.to_log_line().as_bytes())?;
+            if !record.meta.wtg {
+                log.commands += 1;
+            }
+            log.enforce_limits(&self.limits)?;
+        }
+        if !record.meta.wtg {
+            self.failed = exit_code.is_some_and(|code| code != 0);
+            self.remember(record);
+        }
+        Ok(())
+    }
+
+    /// Keep a finished command in memory, dropping the oldest beyond the limits
+    fn remember(&mut self, record: LogRecord) {
+        self.recent_size += record.output.len();
+        self.recent.push_back(record);
+        while self.recent.len() > RECENT_COMMANDS
+            || (self.recent_size > MAX_RECENT_SIZE && self.recent.len() > 1)
+        {
+            if let Some(dropped) = self.recent.pop_front() {
+                self.recent_size -= dropped.output.len();
+            }
+        }
+    }
+
+    fn record_output(&mut self, output: &[u8]) -> io::Result<()> {
+        if self.paused || self.full_screen.is_some() {
+            return Ok(());
+        }
+        if let Some(prompt) = &mut self.pending_prompt {
+            prompt.extend_from_slice(output);
+            if prompt.len() <= MAX_PENDING_PROMPT {
+                return Ok(());
+            }
+            let prompt = self.pending_prompt.take().unwrap_or_default();
+            return self.record_output(&prompt);
+        }
+        let max_output_size = self.limits.max_output_size;
+        let head_len = match &mut self.running {
+            Some(running) => running.cap_output(output, max_output_size),
+            None => output.len(),
+        };
+        self.record_head(&output[..head_len])
+    }
+
+    /// Record output of the running command (or output before the first command)
+    fn record_head(&mut self, output: &[u8]) -> io::Result<()> {
+        self.write_text(output)?;
+        match &mut self.running {
+            Some(running) => running.keep_output(output),
+            None => {
+                self.unattributed_line.extend_from_slice(output);
+                self.unattributed_line = split_last_line(&mut self.unattributed_line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the end of the running command's output held back by `max_output_size`
+    fn record_tail(&mut self) -> io::Result<()> {
+        let tail = match &mut self.running {
+            Some(running) => running.take_tail(),
+            None => return Ok(()),
+        };
+        self.record_head(&tail)
+    }
+
+    /// Write a newline if the text log is not at the start of a line
+    fn start_text_line(&mut self) -> io::Result<()> {
+        if !self.at_line_start {
+            self.write_text(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn write_text(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let (Some(log), Some(&last)) = (&mut self.text_log, bytes.last()) {
+            log.write_all(bytes)?;
+            self.at_line_start = last == b'\n';
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for log in [&mut self.text_log, &mut self.jsonl_log]
+            .into_iter()
+            .flatten()
+        {
+            log.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Remove and return the bytes after the last newline
+fn split_last_line(bytes: &mut Vec<u8>) -> Vec<u8> {
+    let line_start = bytes
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    bytes.split_off(line_start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(recorder: &Recorder) -> Vec<&str> {
+        recorder
+            .recent_commands(None)
+            .map(|record| record.output.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn records_output_between_enters_without_integration() {
+        let mut recorder = Recorder::new(None, None, LogLimits::default());
+        recorder.on_output(b"$ echo hi").unwrap();
+        recorder.on_enter().unwrap();
+        recorder.on_output(b"\r\nhi\r\n$ ls").unwrap();
+        recorder.on_enter().unwrap();
+        recorder.on_output(b"\r\nfile\r\n$ ").unwrap();
+        recorder.on_enter().unwrap();
+        assert_eq!(
+            outputs(&recorder),
+            ["$ echo hi\r\nhi\r\n", "$ ls\r\nfile\r\n"]
+        );
+    }
+
+    #[test]
+    fn splits_the_prompt_from_the_output_tail() {
+        let mut recorder = Recorder::new(None, None, LogLimits::default());
+        recorder.on_enter().unwrap();
+        let output = vec![b'x'; MAX_COMMAND_MEMORY];
+        recorder.on_output(&output).unwrap();
+        recorder.on_output(b"\r\nend\r\n$ ls").unwrap();
+        recorder.on_enter().unwrap();
+        let records: Vec<&LogRecord> = recorder.recent_commands(None).collect();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].output.ends_with("xx\r\nend\r\n"));
+        let output = &records[0].output;
+        // the second output pushed its length out of the tail
+        let notice = "xx\r\n[wtg: 11 bytes omitted]\r\nxx";
+        assert!(output.contains(notice));
+    }
+
+    /// A log path in the temp directory, the log and its rotated log are removed when dropped
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("wtg-recorder-{}-{}", std::process::id(), name));
+            let log = TempLog(path);
+            log.remove();
+            log
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn remove(&self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(with_suffix(&self.0, ".1"));
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    /// A recorder writing one log in `format`
+    fn recorder(log: &TempLog, format: LogFormat, limits: LogLimits) -> Recorder {
+        let file = LogFile::open(log.path(), format, &limits).unwrap();
+        match format {
+            LogFormat::Text => Recorder::new(Some(file), None, limits),
+            LogFormat::Jsonl => Recorder::new(None, Some(file), limits),
+        }
+    }
+
+    /// Start a command as the shell integration reports it
+    fn start(recorder: &mut Recorder, command: &str) {
+        let start = format!("\x1b]133;A\x07$ \x1b]133;C;cmdline_url={}\x07", command);
+        recorder.on_output(start.as_bytes()).unwrap();
+    }
+
+    /// Run a command as the shell integration reports it
+    fn run(recorder: &mut Recorder, command: &str, output: &[u8]) {
+        start(recorder, command);
+        recorder.on_output(output).unwrap();
+        recorder.on_output(b"\x1b]133;D;0\x07").unwrap();
+    }
+
+    fn jsonl_records(log: &TempLog) -> Vec<LogRecord> {
+        fs::read_to_string(log.path())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn jsonl_commands(log: &TempLog) -> Vec<String> {
+        jsonl_records(log)
+            .into_iter()
+            .map(|record| record.meta.command.unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn caps_command_output() {
+        let log = TempLog::new("cap.jsonl");
+        let limits = LogLimits {
+            max_output_size: Some(20),
+            ..Default::default()
+        };
+        let mut recorder = recorder(&log, LogFormat::Jsonl, limits);
+        start(&mut recorder, "cat");
+        for chunk in ["0123456", "789abcdefghij", "ABCDEFGHIJklm", "nopqrst"] {
+            recorder.on_output(chunk.as_bytes()).unwrap();
+        }
+        recorder.on_output(b"\x1b]133;D;0\x07").unwrap();
+        run(&mut recorder, "echo", b"short");
+        let outputs: Vec<String> = jsonl_records(&log)
+            .into_iter()
+            .map(|record| record.output)
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                "$ 0123456789\r\n[wtg: 20 bytes omitted]\r\nklmnopqrst",
+                "$ short"
+            ]
+        );
+    }
+
+    #[test]
+    fn caps_command_output_in_the_text_log() {
+        let log = TempLog::new("cap.log");
+        let limits = LogLimits {
+            max_output_size: Some(20),
+            ..Default::default()
+        };
+        let mut recorder = recorder(&log, LogFormat::Text, limits);
+        run(
+            &mut recorder,
+            "cat",
+            b"0123456789abcdefghijABCDEFGHIJklmnopqrst",
+        );
+        let content = fs::read_to_string(log.path()).unwrap();
+        assert!(content.contains("$ 0123456789\r\n[wtg: 20 bytes omitted]\r\nklmnopqrst\n"));
+    }
+
+    #[test]
+    fn prunes_old_commands_with_slack() {
+        let log = TempLog::new("prune.jsonl");
+        let limits = LogLimits {
+            keep_commands: Some(10),
+            ..Default::default()
+        };
+        let mut recorder = recorder(&log, LogFormat::Jsonl, limits);
+        // 10 commands and 10% more are kept before the log is pruned
+        for i in 1..=11 {
+            run(&mut recorder, &format!("cmd{}", i), b"out");
+        }
+        assert_eq!(jsonl_commands(&log).len(), 11);
+        run(&mut recorder, "cmd12", b"out");
+        let expected: Vec<String> = (3..=12).map(|i| format!("cmd{}", i)).collect();
+        assert_eq!(jsonl_commands(&log), expected);
+        // the count continues from the pruned log
+        run(&mut recorder, "cmd13", b"out");
+        assert_eq!(jsonl_commands(&log).len(), 11);
+    }
+
+    #[test]
+    fn prunes_without_counting_wtg_invocations() {
+        let log = TempLog::new("prune-wtg.jsonl");
+        let limits = LogLimits {
+            keep_commands: Some(2),
+            ..Default::default()
+        };
+        let mut recorder = recorder(&log, LogFormat::Jsonl, limits);
+        run(&mut recorder, "cmd1", b"out");
+        run(&mut recorder, "cmd2", b"out");
+        run(
+            &mut recorder,
+            "wtg",
+            crate::shell::control_sequence("wtg").as_bytes(),
+        );
+        run(&mut recorder, "cmd3", b"out");
+        assert_eq!(jsonl_commands(&log), ["cmd2", "wtg", "cmd3"]);
+    }
+
+    #[test]
+    fn prunes_the_text_log_between_commands() {
+        let log = TempLog::new("prune.log");
+        let limits = LogLimits {
+            keep_commands: Some(10),
+            ..Default::default()
+        };
+        let mut recorder = recorder(&log, LogFormat::Text, limits);
+        for i in 1..=12 {
+            run(&mut recorder, &format!("cmd{}", i), b"out");
+        }
+        // pruned when the next command starts
+        start(&mut recorder, "cmd13");
+        let records = crate::history::read_commands(log.path()).unwrap();
+        let commands: Vec<String> = records
+            .into_iter()
+            .filter_map(|record| record.meta?.command)
+            .collect();
+        let expected: Vec<String> = (3..=12).map(|i| format!("cmd{}", i)).collect();
+        assert_eq!(commands, expected);
+    }
+
+    #[test]
+    fn rotates_large_logs() {
+        let log = TempLog::new("rotate.log");
+        let limits = LogLimits {
+            max_log_size: Some(100),
+            ..Default::default()
+        };
+        let mut recorder = recorder(&log, LogFormat::Text, limits);
+        run(&mut recorder, "cmd1", &[b'x'; 150]);
+        // rotated when the next command starts
+        run(&mut recorder, "cmd2", b"out");
+        let rotated = fs::read_to_string(with_suffix(&log.0, ".1")).unwrap();
+        let current = fs::read_to_string(log.path()).unwrap();
+        assert!(rotated.contains("cmd1") && !rotated.contains("cmd2"));
+        assert!(current.starts_with(NEW_COMMAND_MSG));
+        assert!(current.contains("cmd2") && !current.contains("cmd1"));
+        // a rotated log replaces the previous one
+        run(&mut recorder, "cmd3", &[b'x'; 150]);
+        run(&mut recorder, "cmd4", b"out");
+        let rotated = fs::read_to_string(with_suffix(&log.0, ".1")).unwrap();
+        assert!(rotated.contains("cmd3") && !rotated.contains("cmd1"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Render raw terminal output to the text a user would see on screen.
+//!
+//! The session log holds the raw pty byte stream: colors, cursor movement, carriage return
+//! progress bars, backspaces and other control sequences. Sending these verbatim to the model
+//! wastes tokens and obscures the output, so [`render_terminal_output`] replays the stream on a
+//! minimal virtual screen, keeping only the final visible text:
+//! - `\r` returns to the start of the line, so progress updates overwrite each other
+//! - `\b` and cursor movement sequences move the cursor, later text overwrites earlier text
+//! - erase in line/display sequences clear the erased text
+//! - colors, modes, titles and all other escape sequences are dropped
+//!
+//! The screen has no width or height limit, so absolute cursor positioning (used by full screen
+//! apps) cannot be replayed and is ignored.
+
+const ESC: char = '\x1b';
+const BEL: char = '\x07';
+const TAB_WIDTH: usize = 8;
+
+/// A virtual screen which output is replayed on
+#[derive(Default)]
+struct Screen {
+    lines: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+}
+
+impl Screen {
+    fn line(&mut self) -> &mut Vec<char> {
+        if self.lines.len() <= self.row {
+            self.lines.resize_with(self.row + 1, Vec::new);
+        }
+        &mut self.lines[self.row]
+    }
+
+    fn put(&mut self, c: char) {
+        let col = self.col;
+        let line = self.line();
+        if line.len() <= col {
+            line.resize(col, ' ');
+            line.push(c);
+        } else {
+            line[col] = c;
+        }
+        self.col += 1;
+    }
+
+    fn newline(&mut self) {
+        self.row += 1;
+        self.col = 0;
+        self.line();
+    }
+
+    /// Apply a CSI sequence with the given parameters and final byte
+    fn csi(&mut self, params: &str, action: char) {
+        // private sequences (e.g. `ESC [ ? 25 l`) only change terminal modes
+        if params.starts_with(['?', '>', '<', '=']) {
+            return;
+        }
+        let mut args = params
+            .split(';')
+            .map(|arg| arg.parse::<usize>().unwrap_or(0));
+        let first = args.next().unwrap_or(0);
+        // most movements treat a missing or zero count as 1
+        let count = first.max(1);
+        match action {
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => {
+                self.row += count;
+                self.line();
+            }
+            'C' => self.col += count,
+            'D' => self.col = self.col.saturating_sub(count),
+            'E' => {
+                self.row += count;
+                self.col = 0;
+                self.line();
+            }
+            'F' => {
+                self.row = self.row.saturating_sub(count);
+                self.col = 0;
+            }
+            'G' => self.col = count - 1,
+            'K' => {
+                let col = self.col;
+                let line = self.line();
+                match first {
+                    0 => line.truncate(col),
+                    1 => line.iter_mut().take(col + 1).for_each(|c| *c = ' '),
+                    _ => line.clear(),
+                }
+            }
+            // only erase below, clearing the whole screen would also lose the scrollback
+            'J' if first == 0 => {
+                let col = self.col;
+                self.line().truncate(col);
+                self.lines.truncate(self.row + 1);
+            }
+            _ => {}
+        }
+    }
+
+    fn render(self) -> String {
+        self.lines
+            .iter()
+            .map(|line| line.iter().collect::<String>().trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Render raw terminal output to its final visible text. Lines are separated by `\n` with
+/// trailing whitespace removed.
+pub fn render_terminal_output(raw: &str) -> String {
+    let mut screen = Screen::default();
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => screen.newline(),
+            '\r' => screen.col = 0,
+            '\x08' => screen.col = screen.col.saturating_sub(1),
+            '\t' => screen.col = (screen.col / TAB_WIDTH + 1) * TAB_WIDTH,
+            ESC => match chars.next() {
+                // CSI: parameters and intermediates, then a final byte in `@`..=`~`
+                Some('[') => {
+                    let mut params = String::new();
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            screen.csi(&params, c);
+                            break;
+                        }
+                        params.push(c);
+                    }
+                }
+                // OSC, DCS, SOS, PM and APC strings, terminated by BEL or `ESC \`
+                Some(']' | 'P' | 'X' | '^' | '_') => {
+                    while let Some(c) = chars.next() {
+                        if c == BEL {
+                            break;
+                        }
+                        if c == ESC {
+                            chars.next_if_eq(&'\\');
+                            break;
+                        }
+                    }
+                }
+                // character set selection takes one more character
+                Some('(' | ')' | '*' | '+') => {
+                    chars.next();
+                }
+                // other two character sequences (e.g. keypad modes, save/restore cursor)
+                _ => {}
+            },
+            c if c.is_control() => {}
+            c => screen.put(c),
+        }
+    }
+    screen.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_colors_and_normalizes_line_endings() {
+        assert_eq!(
+            render_terminal_output("\x1b[1;31merror\x1b[0m: failed  \r\nnext\r\n"),
+            "error: failed\nnext\n"
+        );
+    }
+
+    #[test]
+    fn overwrites_carriage_return_updates() {
+        assert_eq!(
+            render_terminal_output("progress 10%\rprogress 100%\ndone"),
+            "progress 100%\ndone"
+        );
+        // a shorter update leaves the end of the longer one, as on screen
+        assert_eq!(render_terminal_output("50%\r1%"), "1%%");
+    }
+
+    #[test]
+    fn applies_backspaces_and_tabs() {
+        assert_eq!(render_terminal_output("abc\x08\x08XY"), "aXY");
+        assert_eq!(render_terminal_output("a\tb"), "a       b");
+    }
+
+    #[test]
+    fn moves_the_cursor() {
+        // up, erase the line, then write over it
+        assert_eq!(
+            render_terminal_output("one\ntwo\n\x1b[2A\x1b[2Kuno\x1b[2B"),
+            "uno\ntwo\n"
+        );
+        assert_eq!(render_terminal_output("abcdef\x1b[3DX\x1b[1GY"), "YbcXef");
+        assert_eq!(render_terminal_output("ab\x1b[2Cc"), "ab  c");
+    }
+
+    #[test]
+    fn erases_in_line_and_below() {
+        assert_eq!(render_terminal_output("abcdef\x1b[3D\x1b[K"), "abc");
+        assert_eq!(render_terminal_output("abcdef\x1b[3D\x1b[1K"), "    ef");
+        assert_eq!(
+            render_terminal_output("keep\nold\nlines\x1b[1F\x1b[J"),
+            "keep\n"
+        );
+    }
+
+    #[test]
+    fn drops_other_sequences() {
+        let raw = "\x1b]0;title\x07\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\\
+                   \x1b[?25l\x1b(B\x1b=\x1bPdata\x1b\\ text\x07";
+        assert_eq!(render_terminal_output(raw), "link text");
+    }
+
+    #[test]
+    fn ignores_unterminated_sequences() {
+        assert_eq!(render_terminal_output("text\x1b[1;3"), "text");
+        assert_eq!(render_terminal_output("text\x1b]0;title"), "text");
+        assert_eq!(render_terminal_output("text\x1b"), "text");
+    }
+}
@@ -1,24 +1,44 @@
 //! Components to manage a WTG session and query/chat with the last command's output.
 
 use std::os::fd::AsFd;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 use libc::{kill, SIGWINCH};
 use nix::pty::{forkpty, ForkptyResult, Winsize};
-use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
-use nix::sys::wait::waitpid;
+use nix::sys::termios::{
+    cfmakeraw, tcgetattr, tcsetattr, LocalFlags, SetArg, SpecialCharacterIndices, Termios,
+};
+use nix::sys::wait::{waitpid, WaitStatus};
 use nix::unistd::{execvp, Pid};
 use signal_hook::iterator::Signals;
 use std::env;
 use std::ffi::CString;
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, Read, Write};
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
-use std::sync::{mpsc, Arc, Mutex};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
 
-use crate::cli::{Model, NEW_COMMAND_MSG};
+use crate::auto::AutoDiagnosis;
+use crate::budget::{estimate_tokens, fit_contexts, output_budget};
+use crate::cli::{ContextArgs, LlmArgs, LogFormat, RunArgs, SessionArgs};
+use crate::control::{ControlClient, ControlServer};
+use crate::diagnostics::focus_output;
 use crate::errors::WtgError;
-use crate::openai::query_chatgpt;
+use crate::history::{
+    format_duration, read_previews, recent_count, select_commands, select_from, CommandIndex,
+    CommandMeta, CommandPreview, CommandRecord, LogRecord,
+};
+use crate::llm::{
+    build_dry_run_provider, build_provider, print_delta, query_llm, query_messages, system_message,
+    ChatMessage, CommandContext, Completion, LlmSettings, Provider,
+};
+use crate::recorder::{LogFile, LogLimits, Recorder};
+use crate::redact::Redactor;
+use crate::sanitize::render_terminal_output;
+use crate::shell::{command_end_position, control_sequence, SessionDir, ShellIntegration};
+
+/// The default EOF character
+const CTRL_D: u8 = 0x04;
 
 /// Convert the original terminal to raw mode so characters are sent immediately to the pty
 /// So the pty can process ANSI escape sequences. Also disable default echo of user input.
@@ -65,10 +85,9 @@ fn get_parent_winsize() -> Winsize {
         ws_ypixel: 0,
     };
 
+    // keeps the default size if stdin is not a terminal
     // SAFETY: the window size pointer is valid and the process stdin has not been closed
-    if unsafe { ioctl(fd, TIOCGWINSZ, &mut ws) } == -1 {
-        panic!("Failed to get window size using ioctl");
-    }
+    unsafe { ioctl(fd, TIOCGWINSZ, &mut ws) };
 
     Winsize {
         ws_row: ws.ws_row,
@@ -108,16 +127,60 @@ fn listen_pty_resize(child_pid_for_resize: Pid, master_fd: RawFd) -> Result<(),
 }
 
 /// Start a WTG session
-pub fn run_session(logfile: &str) -> Result<(), WtgError> {
-    let path = PathBuf::from(logfile);
-    let log = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(path.clone())
-        .expect("Failed to open log file");
-    initialize_env_vars(path)?;
+pub fn run_session(args: &SessionArgs, llm: &LlmArgs) -> Result<(), WtgError> {
+    let auto = AutoDiagnosis::resolve(args, llm)?;
+    let limits = LogLimits::resolve(args)?;
+    let format = args.format();
+    let log = LogFile::open(&args.logfile, format, &limits)?;
+    let mirror = args
+        .mirror
+        .as_deref()
+        .map(|mirror| LogFile::open(mirror, LogFormat::Text, &limits))
+        .transpose()?;
+    let recorder = match format {
+        LogFormat::Text => Recorder::new(Some(log), mirror, limits),
+        LogFormat::Jsonl => Recorder::new(mirror, Some(log), limits),
+    };
+    initialize_env_vars(&args.logfile)?;
+    // holds the control socket and shell hooks, removed when the session ends
+    let dir = SessionDir::create()?;
+    let control = ControlServer::bind(&dir)?;
+    env::set_var("WTG_SOCK", control.path());
+
+    // the child only calls async signal safe functions, so prepare the shell arguments before forking
+    let shell = env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+    let integration = if !args.no_shell_integration {
+        ShellIntegration::prepare(&shell, &dir)?
+    } else {
+        None
+    };
+    let shell_argv = match &integration {
+        Some(integration) => {
+            for (key, value) in &integration.env {
+                env::set_var(key, value);
+            }
+            integration.argv.clone()
+        }
+        None => vec![CString::new(shell).expect("CString failed")],
+    };
 
     println!("Starting wtg session. Type 'exit' to quit.");
+    let recorder = Arc::new(Mutex::new(recorder));
+    control
+        .serve(Arc::clone(&recorder))
+        .expect("Failed to serve the control socket");
+    run_in_pty(&shell_argv, &recorder, auto)?;
+    Ok(())
+}
+
+/// Run `argv` in a new pty, like `script`. The terminal input is forwarded to the pty, and the pty
+/// output is written to the terminal and passed to the recorder. Failed commands are diagnosed
+/// with `auto` if given. Returns once the child exits.
+fn run_in_pty(
+    argv: &[CString],
+    recorder: &Arc<Mutex<Recorder>>,
+    mut auto: Option<AutoDiagnosis>,
+) -> Result<WaitStatus, WtgError> {
     // inherit parent window size, can be resized dynamically
     let window_size = get_parent_winsize();
     // forks a child and parent for the pty
@@ -126,14 +189,20 @@ pub fn run_session(logfile: &str) -> Result<(), WtgError> {
 
     match fork_result {
         ForkptyResult::Parent { child, master } => {
-            // called here, sets the parent's STDIN to raw mode (i.e. the original terminal input), the child is still in cooked mode
+            // called here, sets the parent's STDIN to raw mode (i.e. the original terminal input), the child is still in cooked mode.
+            // input which is not a terminal (e.g. a pipe) is forwarded as is
             let stdin = std::io::stdin();
-            let guard =
-                RawModeGuard::new(stdin).expect("Failed to get terminal attributes for raw mode");
-            guard.enable_raw_mode().expect("Failed to enable raw mode");
+            let stdin_tty = nix::unistd::isatty(stdin.as_raw_fd()).unwrap_or(false);
+            let _guard = if stdin_tty {
+                let guard = RawModeGuard::new(stdin)
+                    .expect("Failed to get terminal attributes for raw mode");
+                guard.enable_raw_mode().expect("Failed to enable raw mode");
+                Some(guard)
+            } else {
+                None
+            };
             let master_fd = master.as_raw_fd();
-            // SAFETY: the fd must be owned and open. The master fd is an OwnedFd and has not been closed.
-            let master_file = unsafe { File::from_raw_fd(master_fd) };
+            let master_file = File::from(master);
             let mut master_reader = master_file
                 .try_clone()
                 .expect("Failed to clone master file");
@@ -142,166 +211,503 @@ pub fn run_session(logfile: &str) -> Result<(), WtgError> {
             // forward resizes to the pty via master fd
             listen_pty_resize(child, master_fd).expect("Failed to listen for pty resize");
 
-            let (enter_tx, enter_rx) = mpsc::channel::<()>();
-            let (truncated_tx, truncated_rx) = mpsc::channel::<()>();
-
             // take user input and write to the master pty
-            std::thread::spawn(move || {
-                // in raw mode, every character is sent immediately to the pty stdin
-                // in canonical mode, the user input is buffered until a newline is entered
-                let stdin = io::stdin();
-                let mut input = stdin.lock();
-                let mut buf = [0u8; 1024];
-                loop {
-                    match input.read(&mut buf) {
-                        Ok(0) => {
-                            break;
-                        }
-                        Ok(n) => {
-                            // log file should only have most recent command output
-                            // truncate the log on enter, indicating a new command has started
-                            if buf[..n].iter().any(|&b| b == b'\n' || b == b'\r') {
-                                let _ = enter_tx.send(());
-                                let _ = truncated_rx.recv();
-                            }
-                            if master_writer.write_all(&buf[..n]).is_err() {
+            {
+                let recorder = Arc::clone(recorder);
+                std::thread::spawn(move || {
+                    // in raw mode, every character is sent immediately to the pty stdin
+                    // in canonical mode, the user input is buffered until a newline is entered
+                    let stdin = io::stdin();
+                    let mut input = stdin.lock();
+                    let mut buf = [0u8; 1024];
+                    let mut at_line_start = true;
+                    loop {
+                        match input.read(&mut buf) {
+                            Ok(0) => {
+                                // the end of piped input is passed on as the EOF character, twice
+                                // after a partial line (the first only ends the line)
+                                if !stdin_tty {
+                                    let eof = tcgetattr(&master_writer)
+                                        .map(|termios| {
+                                            termios.control_chars
+                                                [SpecialCharacterIndices::VEOF as usize]
+                                        })
+                                        .unwrap_or(CTRL_D);
+                                    let count = if at_line_start { 1 } else { 2 };
+                                    let _ = master_writer.write_all(&vec![eof; count]);
+                                }
                                 break;
                             }
+                            Ok(n) => {
+                                at_line_start = buf[n - 1] == b'\n';
+                                // without shell integration, Enter indicates a new command has started.
+                                // mark the log before forwarding the input, so the command output follows the mark
+                                if buf[..n].iter().any(|&b| b == b'\n' || b == b'\r') {
+                                    let _ = recorder.lock().unwrap().on_enter();
+                                }
+                                if master_writer.write_all(&buf[..n]).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(_) => break,
                         }
-                        Err(_) => break,
-                    }
-                }
-            });
-
-            let log = Arc::new(Mutex::new(log));
-            {
-                let log = Arc::clone(&log);
-                std::thread::spawn(move || {
-                    // Clears the log file on Enter keypress
-                    while let Ok(()) = enter_rx.recv() {
-                        let mut log = log.lock().unwrap();
-                        // write message to log indicating a new command has started
-                        log.write_all(NEW_COMMAND_MSG.as_bytes()).unwrap();
-                        // sends "ack" that log file has been cleared
-                        let _ = truncated_tx.send(());
                     }
                 });
             }
 
             let mut buf = [0u8; 1024];
             loop {
-                let n = master_reader
-                    .read(&mut buf)
-                    .expect("Error reading from PTY");
-                if n == 0 {
-                    break;
-                }
-                {
-                    let stdout = io::stdout();
-                    // acquire lock inside loop so it is periodically released
-                    // otherwise, any use of `println!` for debugging would block because the `stdout` lock is always held
-                    let mut out = stdout.lock();
-                    out.write_all(&buf[..n]).expect("Failed to write to stdout");
-                    out.flush().unwrap();
-                }
-                {
-                    let mut log = log.lock().unwrap();
-                    log.write_all(&buf[..n]).expect("Failed to write to log");
-                    log.flush().unwrap();
+                // the read fails (`EIO` on Linux) or returns 0 once the child exits
+                let n = match master_reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let output = &buf[..n];
+                let failed = {
+                    let mut recorder = recorder.lock().unwrap();
+                    recorder.on_output(output).expect("Failed to write to log");
+                    recorder.take_failed()
+                };
+                let stdout = io::stdout();
+                // acquire lock inside loop so it is periodically released
+                // otherwise, any use of `println!` for debugging would block because the `stdout` lock is always held
+                let mut out = stdout.lock();
+                match (&mut auto, failed) {
+                    (Some(auto), Some(record)) => {
+                        // the diagnosis goes below the command's output, before the next prompt
+                        let end = command_end_position(output).unwrap_or(n);
+                        out.write_all(&output[..end])
+                            .expect("Failed to write to stdout");
+                        auto.diagnose(record, &mut out)
+                            .expect("Failed to write to stdout");
+                        out.write_all(&output[end..])
+                            .expect("Failed to write to stdout");
+                    }
+                    _ => out.write_all(output).expect("Failed to write to stdout"),
                 }
+                out.flush().unwrap();
             }
-            waitpid(child, None).expect("Failed to wait on child");
+            Ok(waitpid(child, None)?)
         }
         ForkptyResult::Child => {
             // the child starts a new tty and is still in cooked mode
-            let shell = env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
-            let shell_c = CString::new(shell).expect("CString failed");
-            let args = [shell_c.clone()];
-            execvp(&shell_c, &args).expect("execvp failed");
+            let Err(_) = execvp(&argv[0], argv);
+            // the parent may have other threads, so nothing is allocated (e.g. by `eprintln!`)
+            let program = argv[0].as_bytes();
+            for message in [b"wtg: failed to run ".as_slice(), program, b"\n"] {
+                // SAFETY: `write` is async signal safe and the buffer is valid for its length
+                unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+            }
+            // exit like a shell which cannot find the command
+            // SAFETY: `_exit` skips the parent's exit handlers, which must not run in the child
+            unsafe { libc::_exit(127) }
+        }
+    }
+}
+
+/// Run a single command in a pty and record it, without a shell session. Returns the command's
+/// exit status, or 128 plus the signal number if it was killed by a signal, like a shell.
+pub fn run_command(args: &RunArgs, llm: LlmArgs) -> Result<i32, WtgError> {
+    // inside a session, the command is already recorded in the session's log
+    let logfile = args
+        .logfile
+        .clone()
+        .or_else(|| match env::var_os("WTG_SESSION") {
+            Some(_) => None,
+            None => env::var("WTG_LOG").ok(),
+        });
+    let limits = LogLimits::from_config()?;
+    let mut recorder = match &logfile {
+        Some(logfile) => {
+            let format = LogFormat::from_path(logfile);
+            let log = LogFile::open(logfile, format, &limits)?;
+            match format {
+                LogFormat::Text => Recorder::new(Some(log), None, limits),
+                LogFormat::Jsonl => Recorder::new(None, Some(log), limits),
+            }
+        }
+        None => Recorder::new(None, None, limits),
+    };
+    let argv: Vec<CString> = args
+        .command
+        .iter()
+        .map(|arg| CString::new(arg.as_str()).expect("CString failed"))
+        .collect();
+    recorder.start_command(shell_join(&args.command))?;
+    let recorder = Arc::new(Mutex::new(recorder));
+    let exit_code = match run_in_pty(&argv, &recorder, None)? {
+        WaitStatus::Exited(_, code) => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => 1,
+    };
+    let failed = {
+        let mut recorder = recorder.lock().unwrap();
+        recorder.end_command(exit_code)?;
+        let last = recorder.recent_commands(Some(1)).next().cloned();
+        last
+    };
+    if let (true, Some(record)) = (args.explain && exit_code != 0, failed) {
+        // the command's exit status is kept even if the model can't be queried
+        if let Err(e) = explain_failure(record, exit_code, llm) {
+            eprintln!("{}", e);
         }
     }
+    Ok(exit_code)
+}
+
+/// Join the arguments into a command line, quoting those a shell would split or expand
+fn shell_join(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_./=:,+@%".contains(&b));
+            if plain {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Ask the model to explain the output of a command which failed under `wtg run`
+fn explain_failure(record: LogRecord, exit_code: i32, llm: LlmArgs) -> Result<(), WtgError> {
+    let mut contexts =
+        vec![CommandRecord::from_log_record(CommandIndex::FromEnd(1), record).to_context()];
+    redact_contexts(&mut contexts, false)?;
+    let settings = LlmSettings::resolve(&llm)?;
+    let provider = build_provider(&settings)?;
+    fit_to_context_window(&mut contexts, &settings, &settings.prompt, None);
+    tag_wtg_output(exit_code)?;
+    eprintln!("\n[wtg: asking {} why the command failed]", settings.model);
+    let _ = query_llm(
+        provider.as_ref(),
+        &contexts,
+        &settings.prompt,
+        &settings.model,
+        &mut print_delta,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Error querying {}: {}", provider.name(), e);
+        Completion::default()
+    });
+    println!();
     Ok(())
 }
 
 /// Initialize the default environment variables for the WTG session
 fn initialize_env_vars<P: AsRef<Path>>(path: P) -> Result<(), WtgError> {
     env::set_var("WTG_LOG", path.as_ref().canonicalize()?);
+    env::set_var("WTG_SESSION", std::process::id().to_string());
     Ok(())
 }
 
-/// Get the string contents of the log file
-fn get_log_content(logfile: String) -> Result<String, WtgError> {
-    let file = File::open(&logfile).map_err(|_| WtgError::LogFileOpenError { logfile })?;
-    let mut reader = BufReader::new(file);
-    let mut log_vec = Vec::new();
-    reader
-        .read_to_end(&mut log_vec)
-        .expect("Failed to read log file");
-    Ok(String::from_utf8_lossy(&log_vec).to_string())
+/// Flag the running command as a `wtg` invocation in the enclosing session's log, so its output
+/// is skipped by later queries. Does nothing outside a session.
+fn tag_wtg_command() {
+    if env::var_os("WTG_SESSION").is_none() {
+        return;
+    }
+    if let Ok(mut tty) = OpenOptions::new().write(true).open("/dev/tty") {
+        let _ = tty.write_all(control_sequence("wtg").as_bytes());
+    }
+}
+
+/// End the running command's output in the enclosing session's log with `exit_code`, and flag
+/// the rest as a `wtg` invocation, so `wtg`'s own output which follows is skipped by later
+/// queries. Does nothing outside a session.
+fn tag_wtg_output(exit_code: i32) -> io::Result<()> {
+    if env::var_os("WTG_SESSION").is_none() {
+        return Ok(());
+    }
+    let mut tty = OpenOptions::new().write(true).open("/dev/tty")?;
+    tty.write_all(control_sequence(&format!("mark;{}", exit_code)).as_bytes())?;
+    tty.write_all(control_sequence("wtg").as_bytes())
+}
+
+/// Pause or resume recording in the enclosing session. The request is written to the terminal
+/// as a private OSC sequence, which the session's recorder picks out of the pty output.
+pub fn run_pause(pause: bool) -> Result<(), WtgError> {
+    if env::var_os("WTG_SESSION").is_none() {
+        return Err(WtgError::NotInSession);
+    }
+    tag_wtg_command();
+    let mut tty = OpenOptions::new().write(true).open("/dev/tty")?;
+    if pause {
+        tty.write_all(control_sequence("pause").as_bytes())?;
+        // written after the sequence, so the message is not recorded
+        println!("Recording paused, run `wtg resume` to resume.");
+    } else {
+        println!("Recording resumed.");
+        tty.write_all(control_sequence("resume").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Extract the output of the selected commands from the log file
+fn extract_context_from_log(
+    logfile: &str,
+    args: &ContextArgs,
+) -> Result<Vec<CommandContext>, WtgError> {
+    let selected = match select_recent_from_session(args)? {
+        Some(selected) => selected,
+        None => select_commands(logfile, args.cmd, args.last)?,
+    };
+    if let Some((record, Some(meta))) = selected.last().map(|record| (record, &record.meta)) {
+        if args.cmd.is_none() && args.last.is_none() && record.index != CommandIndex::FromEnd(1) {
+            eprintln!(
+                "Using command {}, the most recent failing command ({}). Use `--cmd -1` for the most recent command.",
+                record.index,
+                meta.summary()
+            );
+        }
+    }
+    Ok(selected.iter().map(CommandRecord::to_context).collect())
+}
+
+/// Select the commands from those kept in memory by the enclosing session, through its control
+/// socket. Returns `None` if there is no session, another log file was given, or the session has
+/// fewer commands in memory than the selection needs (e.g. an older command in the log). A
+/// failing command in memory is enough for the default selection.
+fn select_recent_from_session(args: &ContextArgs) -> Result<Option<Vec<CommandRecord>>, WtgError> {
+    if args.logfile.is_some() {
+        return Ok(None);
+    }
+    let (Some(count), Some(mut client)) =
+        (recent_count(args.cmd, args.last), ControlClient::from_env())
+    else {
+        return Ok(None);
+    };
+    let records = client.recent_commands(count)?;
+    // the most recent failing command in memory is also the most recent one in the log
+    let has_failure = || {
+        records
+            .iter()
+            .any(|record| record.meta.as_ref().is_some_and(CommandMeta::failed))
+    };
+    let default_selection = args.cmd.is_none() && args.last.is_none();
+    if records.len() < count && !(default_selection && has_failure()) {
+        return Ok(None);
+    }
+    select_from(records, "the session", args.cmd, args.last).map(Some)
+}
+
+/// Resolve the log file from the CLI arg or `WTG_LOG` env var
+fn resolve_logfile(logfile: Option<String>) -> Result<String, WtgError> {
+    logfile
+        .or_else(|| env::var("WTG_LOG").ok())
+        .ok_or(WtgError::NoLogFile)
+}
+
+/// Redact the secrets in the contexts. With `show_redactions`, the redactions are printed
+/// instead and `false` is returned, so the caller exits without querying.
+fn redact_contexts(
+    contexts: &mut [CommandContext],
+    show_redactions: bool,
+) -> Result<bool, WtgError> {
+    let redactions = Redactor::load()?.redact_contexts(contexts);
+    if !show_redactions {
+        return Ok(true);
+    }
+    if redactions.iter().all(Vec::is_empty) {
+        println!("No secrets found.");
+    }
+    for (context, redactions) in contexts.iter().zip(&redactions) {
+        if redactions.is_empty() {
+            continue;
+        }
+        println!(
+            "{} secret(s) redacted from `{}`:",
+            redactions.len(),
+            context.command.as_deref().unwrap_or("piped input")
+        );
+        for redaction in redactions {
+            println!("  {}", redaction);
+        }
+    }
+    Ok(false)
+}
+
+/// Reduce each output to the snippets around its errors, if any were found
+fn focus_contexts(contexts: &mut [CommandContext]) {
+    for context in contexts {
+        if let Some(focused) = focus_output(&context.output) {
+            context.output = focused;
+        }
+    }
+}
+
+/// Shorten the outputs to fit the model's context window, if the window is known.
+/// Outputs are summarized with `summarizer` if given.
+fn fit_to_context_window(
+    contexts: &mut [CommandContext],
+    settings: &LlmSettings,
+    prompt: &str,
+    summarizer: Option<&dyn Provider>,
+) {
+    let Some(context_window) = settings.model_info.context_window else {
+        return;
+    };
+    let budget = output_budget(context_window, prompt);
+    let shortened = fit_contexts(
+        contexts,
+        budget,
+        summarizer.map(|provider| (provider, &settings.model)),
+    );
+    if shortened > 0 {
+        eprintln!(
+            "Shortened the output of {} command(s) to fit the {} token context window of {}.",
+            shortened, context_window, settings.model
+        );
+    }
+}
+
+/// Resolve the LLM settings and build the provider. A dry run sends no request, so the API key
+/// is neither looked up nor required.
+fn resolve_provider(
+    llm: &LlmArgs,
+    dry_run: bool,
+) -> Result<(LlmSettings, Box<dyn Provider>), WtgError> {
+    if dry_run {
+        let settings = LlmSettings::resolve_without_key(llm)?;
+        let provider = build_dry_run_provider(&settings);
+        return Ok((settings, provider));
+    }
+    let settings = LlmSettings::resolve(llm)?;
+    let provider = build_provider(&settings)?;
+    Ok((settings, provider))
+}
+
+/// Print the request which would be sent instead of sending it
+fn print_dry_run(
+    provider: &dyn Provider,
+    settings: &LlmSettings,
+    messages: &[ChatMessage],
+) -> io::Result<()> {
+    let tokens: usize = messages
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum();
+    // written to a locked stdout so a closed pipe (e.g. `| head`) is an error instead of a panic
+    let mut out = io::stdout().lock();
+    writeln!(out, "Provider: {}", provider.name())?;
+    writeln!(out, "Endpoint: {}", provider.endpoint())?;
+    writeln!(out, "Model: {}", settings.model)?;
+    match settings.model_info.context_window {
+        Some(context_window) => writeln!(
+            out,
+            "Estimated tokens: {} (context window: {})",
+            tokens, context_window
+        )?,
+        None => writeln!(out, "Estimated tokens: {}", tokens)?,
+    }
+    for message in messages {
+        writeln!(out, "\n--- {} ---\n{}", message.role, message.content)?;
+    }
+    Ok(())
 }
 
-/// Extract the output of the last command from the log file
-fn extract_context_from_log(logfile: &str) -> Result<String, WtgError> {
-    let log_content = get_log_content(logfile.to_string())?;
-    // Only take the contents of the log file roughly between the second to last `NEW_COMMAND_MSG` and
-    // the last `NEW_COMMAND_MSG`, since the logs following the last correspond to the current
-    // `wtg query` command. Also takes the entire line of the second to last `NEW_COMMAND_MSG`
-    let last_idx = log_content
-        .rfind(NEW_COMMAND_MSG)
-        .ok_or(WtgError::NoCommandRun {
-            logfile: logfile.to_string(),
-        })?;
-    let last_line_start = log_content[..last_idx]
-        .rfind('\n')
-        .map(|i| i + 1)
-        .unwrap_or(0);
-    let second_to_last_idx =
-        log_content[..last_idx]
-            .rfind(NEW_COMMAND_MSG)
-            .ok_or(WtgError::NoCommandRun {
-                logfile: logfile.to_string(),
-            })?;
-    let second_to_last_line_start = log_content[..second_to_last_idx]
-        .rfind('\n')
-        .map(|i| i + 1)
-        .unwrap_or(0);
-    let mut context = log_content[second_to_last_line_start..last_line_start].to_string();
-    // strip the `wtg` inserted `NEW_COMMAND_MSG` delimiter from the context
-    context = context.replace(NEW_COMMAND_MSG, "");
-    Ok(context)
+/// Whether to summarize outputs which don't fit the context window. Summaries take extra model
+/// requests, so they are skipped in a dry run.
+fn summarize(context_args: &ContextArgs) -> bool {
+    if context_args.summarize && context_args.dry_run {
+        eprintln!("Skipping --summarize in a dry run, outputs are truncated instead.");
+    }
+    context_args.summarize && !context_args.dry_run
 }
 
-/// Run a GPT query using the last log output as context
+/// Run a GPT query using the selected commands' log output as context
 pub fn run_query(
-    logfile: Option<String>,
+    context_args: ContextArgs,
     prompt: Option<String>,
-    model: Option<Model>,
+    llm: LlmArgs,
 ) -> Result<(), WtgError> {
+    tag_wtg_command();
     let stdin_fileno = io::stdin().as_raw_fd();
-    let context = if !nix::unistd::isatty(stdin_fileno).unwrap_or(false) {
+    let mut contexts = if !nix::unistd::isatty(stdin_fileno).unwrap_or(false) {
         let mut piped_input = String::new();
         io::stdin().read_to_string(&mut piped_input).unwrap();
-        piped_input
+        vec![CommandContext::from_output(render_terminal_output(
+            &piped_input,
+        ))]
     } else {
-        let logfile = logfile.unwrap_or_else(|| env::var("WTG_LOG").expect("WTG_LOG not set"));
-        extract_context_from_log(&logfile)?
+        extract_context_from_log(
+            &resolve_logfile(context_args.logfile.clone())?,
+            &context_args,
+        )?
     };
-    let _ = query_chatgpt(&context, prompt.as_deref(), model).unwrap_or_else(|e| {
-        eprintln!("Error querying ChatGPT: {}", e);
-        String::new()
+    if !redact_contexts(&mut contexts, context_args.show_redactions)? {
+        return Ok(());
+    }
+    let (settings, provider) = resolve_provider(&llm, context_args.dry_run)?;
+    let prompt = prompt.unwrap_or(settings.prompt.clone());
+    if context_args.focus {
+        focus_contexts(&mut contexts);
+    }
+    fit_to_context_window(
+        &mut contexts,
+        &settings,
+        &prompt,
+        summarize(&context_args).then_some(provider.as_ref()),
+    );
+    if context_args.dry_run {
+        print_dry_run(
+            provider.as_ref(),
+            &settings,
+            &query_messages(&contexts, &prompt),
+        )?;
+        return Ok(());
+    }
+    let _ = query_llm(
+        provider.as_ref(),
+        &contexts,
+        &prompt,
+        &settings.model,
+        &mut print_delta,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Error querying {}: {}", provider.name(), e);
+        Completion::default()
     });
+    println!();
     Ok(())
 }
 
-/// Start a chat using the last log output as context
-pub fn run_chat(logfile: Option<String>, model: Option<Model>) -> Result<(), WtgError> {
-    // sanity check chat is running from a tty
+/// Start a chat using the selected commands' log output as context
+pub fn run_chat(context_args: ContextArgs, llm: LlmArgs) -> Result<(), WtgError> {
+    tag_wtg_command();
+    // sanity check chat is running from a tty, unless only the request is printed
     let stdin_fileno = io::stdin().as_raw_fd();
-    if !nix::unistd::isatty(stdin_fileno).unwrap_or(false) {
+    if !context_args.dry_run && !nix::unistd::isatty(stdin_fileno).unwrap_or(false) {
         return Err(WtgError::ChatNotTty);
     }
-    let logfile = logfile.unwrap_or_else(|| env::var("WTG_LOG").expect("WTG_LOG not set"));
-    let mut chat_context = extract_context_from_log(&logfile)?;
+    let mut contexts = extract_context_from_log(
+        &resolve_logfile(context_args.logfile.clone())?,
+        &context_args,
+    )?;
+    if !redact_contexts(&mut contexts, context_args.show_redactions)? {
+        return Ok(());
+    }
+    let (settings, provider) = resolve_provider(&llm, context_args.dry_run)?;
+    if context_args.focus {
+        focus_contexts(&mut contexts);
+    }
+    fit_to_context_window(
+        &mut contexts,
+        &settings,
+        "",
+        summarize(&context_args).then_some(provider.as_ref()),
+    );
+    // the system message only holds the log context, each turn is appended as its own message
+    let mut messages = vec![system_message(&contexts)];
+    if context_args.dry_run {
+        print_dry_run(provider.as_ref(), &settings, &messages)?;
+        return Ok(());
+    }
+    let model = settings.model;
     println!("(type 'exit' ('e') or 'quit' ('q') to end chat)");
     loop {
         let prompt_text = {
@@ -324,12 +730,82 @@ pub fn run_chat(logfile: Option<String>, model: Option<Model>) -> Result<(), Wtg
             }
             trimmed
         };
-        let response =
-            query_chatgpt(&chat_context, Some(&prompt_text), model).unwrap_or_else(|e| {
-                eprintln!("Error querying ChatGPT: {}", e);
-                String::new()
-            });
-        chat_context.push_str(&format!("\nuser: {}\nassistant: {}", prompt_text, response));
+        messages.push(ChatMessage::user(prompt_text));
+        let response = provider.stream_chat(&model.to_string(), &messages, &mut print_delta);
+        println!();
+        match response {
+            Ok(completion) => messages.push(ChatMessage::assistant(completion.content)),
+            Err(e) => {
+                eprintln!("Error querying {}: {}", provider.name(), e);
+                // drop the unanswered turn so user and assistant messages keep alternating
+                messages.pop();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Truncate a line to at most `width` characters for display
+fn truncate_line(line: &str, width: usize) -> String {
+    if line.chars().count() <= width {
+        line.to_string()
+    } else {
+        let mut truncated: String = line.chars().take(width.saturating_sub(1)).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+/// Human readable byte size
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// List the commands recorded in the log file
+pub fn run_history(logfile: Option<String>) -> Result<(), WtgError> {
+    tag_wtg_command();
+    let logfile = resolve_logfile(logfile)?;
+    // only the start of each output is rendered
+    let previews = read_previews(&logfile)?;
+    if previews.is_empty() {
+        return Err(WtgError::NoCommandRun { logfile });
+    }
+    println!(
+        "{:>5}  {:<40}  {:<40}  {:>9}  {:>4}  {:>8}",
+        "INDEX", "COMMAND", "OUTPUT", "SIZE", "EXIT", "TIME"
+    );
+    for CommandPreview { record, size } in &previews {
+        let exit_code = record
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.exit_code)
+            .map(|code| code.to_string())
+            .unwrap_or_default();
+        let duration = record
+            .meta
+            .as_ref()
+            .map(|meta| format_duration(meta.duration()))
+            .unwrap_or_default();
+        println!(
+            "{:>5}  {:<40}  {:<40}  {:>9}  {:>4}  {:>8}",
+            record.index.to_string(),
+            truncate_line(record.command_line(), 40),
+            truncate_line(record.first_output_line(), 40),
+            format_size(*size),
+            exit_code,
+            duration,
+        );
     }
     Ok(())
 }
@@ -0,0 +1,599 @@
+//! Shell integration for WTG sessions.
+//!
+//! For bash (4.4+), zsh and fish, the session shell is started with hooks which emit
+//! [OSC 133](https://gitlab.freedesktop.org/Per_Bothner/specifications/blob/master/proposals/semantic-prompts.md)
+//! markers around each prompt and command:
+//! - `OSC 133;A`: a prompt is about to be printed
+//! - `OSC 133;C;cmdline_url=<command>`: a command was entered and is about to run. The
+//!   command line is percent-encoded and omitted if unknown
+//! - `OSC 133;D;<exit code>`: the command finished
+//!
+//! Before each prompt, the hooks also report the working directory with `OSC 7;file://<host><path>`.
+//!
+//! `wtg` commands run inside the session control it with private `OSC 7767` sequences (see
+//! [`control_sequence`]), e.g. `wtg pause` writes `OSC 7767;pause` to the terminal. Each `wtg`
+//! command also announces itself with `OSC 7767;wtg`, so its output is not mistaken for the
+//! output of a command to ask about.
+//!
+//! [`OscParser`] picks these markers out of the pty output, which gives exact command boundaries
+//! instead of the Enter key heuristic used for other shells. It also picks out the switches to
+//! and from the alternate screen used by full-screen apps (e.g. vim, less, htop), whose redraws
+//! are not worth recording.
+
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const BASH_HOOKS: &str = include_str!("shell/wtg.bash");
+const ZSH_ENV: &str = include_str!("shell/wtg.zshenv");
+const ZSH_HOOKS: &str = include_str!("shell/wtg.zshrc");
+const FISH_HOOKS: &str = include_str!("shell/wtg.fish");
+
+/// The private OSC number of `wtg` control sequences
+const WTG_OSC: &str = "7767";
+
+/// The longest OSC sequence which is buffered, longer sequences are passed through as output
+const MAX_OSC_LEN: usize = 64 * 1024;
+/// The longest CSI sequence which is buffered, longer sequences are passed through as output
+const MAX_CSI_LEN: usize = 64;
+/// Private modes which switch to the alternate screen (`CSI ? <mode> h`) and back (`CSI ? <mode> l`)
+const ALTERNATE_SCREEN_MODES: &[&str] = &["?1049", "?1047", "?47"];
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Shells with supported integration hooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellKind {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl ShellKind {
+    /// Detect the shell from its path, e.g. `/bin/zsh`
+    pub fn detect(shell: &str) -> Option<Self> {
+        match Path::new(shell).file_name()?.to_str()? {
+            "bash" => Some(ShellKind::Bash),
+            "zsh" => Some(ShellKind::Zsh),
+            "fish" => Some(ShellKind::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// A private directory for the files of a session (the control socket and shell hooks), removed with its
+/// content when this is dropped
+pub struct SessionDir {
+    path: PathBuf,
+}
+
+impl SessionDir {
+    /// Create a new directory with a random name, only accessible to the current user, in
+    /// `XDG_RUNTIME_DIR` if set, otherwise in the temp directory. Like `mkdtemp`, the directory
+    /// must not exist yet, so another user cannot plant files in it.
+    pub fn create() -> io::Result<Self> {
+        let parent = env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_dir())
+            .unwrap_or_else(env::temp_dir);
+        let path = nix::unistd::mkdtemp(&parent.join("wtg-XXXXXX"))?;
+        Ok(SessionDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SessionDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// The hook files for the session shell and how to start the shell with them loaded
+pub struct ShellIntegration {
+    /// Arguments to start the shell with, including the shell itself
+    pub argv: Vec<CString>,
+    /// Env vars to set for the shell
+    pub env: Vec<(String, OsString)>,
+}
+
+impl ShellIntegration {
+    /// Write the hook files for `shell` to the session directory. Returns `None` if the shell is
+    /// not supported.
+    pub fn prepare(shell: &str, dir: &SessionDir) -> io::Result<Option<Self>> {
+        let Some(kind) = ShellKind::detect(shell) else {
+            return Ok(None);
+        };
+        let dir = dir.path();
+        let shell_c = to_cstring(shell)?;
+        let mut env = Vec::new();
+        let argv = match kind {
+            ShellKind::Bash => {
+                let rcfile = dir.join("wtg.bash");
+                fs::write(&rcfile, BASH_HOOKS)?;
+                vec![
+                    shell_c,
+                    to_cstring("--rcfile")?,
+                    to_cstring(rcfile.as_os_str())?,
+                    to_cstring("-i")?,
+                ]
+            }
+            ShellKind::Zsh => {
+                fs::write(dir.join(".zshenv"), ZSH_ENV)?;
+                fs::write(dir.join(".zshrc"), ZSH_HOOKS)?;
+                let user_zdotdir = env::var_os("ZDOTDIR")
+                    .or_else(|| env::var_os("HOME"))
+                    .unwrap_or_default();
+                env.push(("WTG_USER_ZDOTDIR".to_string(), user_zdotdir));
+                env.push(("ZDOTDIR".to_string(), dir.as_os_str().to_owned()));
+                vec![shell_c]
+            }
+            ShellKind::Fish => {
+                let init = dir.join("wtg.fish");
+                fs::write(&init, FISH_HOOKS)?;
+                let mut source = OsString::from("source ");
+                source.push(fish_quote(init.as_os_str()));
+                vec![shell_c, to_cstring("--init-command")?, to_cstring(source)?]
+            }
+        };
+        Ok(Some(ShellIntegration { argv, env }))
+    }
+}
+
+/// Quote `s` as a single fish argument. Inside single quotes, fish only treats `\\` and `\'`
+/// as escapes.
+fn fish_quote(s: &OsStr) -> OsString {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    let mut quoted = vec![b'\''];
+    for &b in s.as_bytes() {
+        if b == b'\\' || b == b'\'' {
+            quoted.push(b'\\');
+        }
+        quoted.push(b);
+    }
+    quoted.push(b'\'');
+    OsString::from_vec(quoted)
+}
+
+fn to_cstring(s: impl Into<OsString>) -> io::Result<CString> {
+    use std::os::unix::ffi::OsStringExt;
+    CString::new(s.into().into_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// A marker picked out of the pty output: a shell integration marker emitted by the session
+/// shell, a `wtg` control sequence or an alternate screen switch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellEvent {
+    /// A prompt is about to be printed
+    PromptStart,
+    /// A command was entered and is about to run
+    CommandStart { command: Option<String> },
+    /// The command finished
+    CommandEnd { exit_code: Option<i32> },
+    /// The shell's working directory, reported before each prompt
+    WorkingDirectory { path: String },
+    /// `wtg pause` was run in the session
+    Pause,
+    /// `wtg resume` was run in the session
+    Resume,
+    /// The running command is a `wtg` invocation
+    WtgCommand,
+    /// End the running command's output here with `exit_code`, and record the rest as a new
+    /// command (e.g. the explanation printed by `wtg run --explain`)
+    Mark { exit_code: Option<i32> },
+    /// A full-screen app switched to the alternate screen (`active`), or back to the main screen
+    AlternateScreen { active: bool },
+}
+
+impl ShellEvent {
+    /// Parse the payload of an OSC sequence (without the `ESC ]` prefix and terminator)
+    fn parse(payload: &[u8]) -> Option<Self> {
+        let payload = std::str::from_utf8(payload).ok()?;
+        if let Some(url) = payload.strip_prefix("7;file://") {
+            // the path starts after the host name
+            let path = &url[url.find('/')?..];
+            return Some(ShellEvent::WorkingDirectory {
+                path: percent_decode(path),
+            });
+        }
+        if let Some(command) = payload
+            .strip_prefix(WTG_OSC)
+            .and_then(|payload| payload.strip_prefix(';'))
+        {
+            return match command {
+                "pause" => Some(ShellEvent::Pause),
+                "resume" => Some(ShellEvent::Resume),
+                "wtg" => Some(ShellEvent::WtgCommand),
+                "mark" => Some(ShellEvent::Mark { exit_code: None }),
+                _ => command
+                    .strip_prefix("mark;")
+                    .and_then(|code| code.parse().ok())
+                    .map(|code| ShellEvent::Mark {
+                        exit_code: Some(code),
+                    }),
+            };
+        }
+        let mut params = payload.strip_prefix("133;")?.split(';');
+        match params.next()? {
+            "A" => Some(ShellEvent::PromptStart),
+            "C" => Some(ShellEvent::CommandStart {
+                command: params
+                    .find_map(|param| param.strip_prefix("cmdline_url="))
+                    .map(percent_decode),
+            }),
+            "D" => Some(ShellEvent::CommandEnd {
+                exit_code: params.next().and_then(|code| code.parse().ok()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The private OSC sequence which sends `command` to the session recorder
+pub fn control_sequence(command: &str) -> String {
+    format!("\x1b]{};{}\x07", WTG_OSC, command)
+}
+
+/// The position just past the last complete `OSC 133;D` (command end) marker in `bytes`, e.g. to
+/// write a finished command's output separately from the prompt which follows it
+pub fn command_end_position(bytes: &[u8]) -> Option<usize> {
+    const MARKER: &[u8] = b"\x1b]133;D";
+    let start = bytes
+        .windows(MARKER.len())
+        .rposition(|window| window == MARKER)?;
+    let marker = &bytes[start..];
+    (MARKER.len()..marker.len()).find_map(|i| match marker[i] {
+        BEL => Some(start + i + 1),
+        ESC if marker.get(i + 1) == Some(&b'\\') => Some(start + i + 2),
+        _ => None,
+    })
+}
+
+/// Decode a percent-encoded string, invalid UTF-8 is replaced
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A piece of pty output, split on shell integration markers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PtyChunk {
+    Output(Vec<u8>),
+    Event(ShellEvent),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ParserState {
+    #[default]
+    Ground,
+    /// After an `ESC`
+    Escape,
+    /// Inside a CSI sequence (after `ESC [`)
+    Csi,
+    /// Inside an OSC sequence (after `ESC ]`)
+    Osc,
+    /// After an `ESC` inside an OSC sequence, i.e. possibly the `ESC \` terminator
+    OscEscape,
+}
+
+/// Streaming parser which separates OSC 133, OSC 7 and `wtg` control sequences, and alternate
+/// screen switches, from the rest of the pty output.
+/// Markers may be split across reads, so partial sequences are held until complete.
+#[derive(Debug, Default)]
+pub struct OscParser {
+    state: ParserState,
+    /// The escape sequence being parsed
+    seq: Vec<u8>,
+}
+
+impl OscParser {
+    /// Feed the next bytes read from the pty
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<PtyChunk> {
+        let mut chunks = Vec::new();
+        let mut output = Vec::new();
+        for &b in bytes {
+            self.step(b, &mut output, &mut chunks);
+        }
+        if !output.is_empty() {
+            chunks.push(PtyChunk::Output(output));
+        }
+        chunks
+    }
+
+    fn step(&mut self, b: u8, output: &mut Vec<u8>, chunks: &mut Vec<PtyChunk>) {
+        match self.state {
+            ParserState::Ground => {
+                if b == ESC {
+                    self.state = ParserState::Escape;
+                    self.seq.push(b);
+                } else {
+                    output.push(b);
+                }
+            }
+            ParserState::Escape => {
+                if b == b']' {
+                    self.state = ParserState::Osc;
+                    self.seq.push(b);
+                } else if b == b'[' {
+                    self.state = ParserState::Csi;
+                    self.seq.push(b);
+                } else {
+                    // not an OSC sequence, pass it through
+                    output.append(&mut self.seq);
+                    self.state = ParserState::Ground;
+                    self.step(b, output, chunks);
+                }
+            }
+            ParserState::Csi => {
+                self.seq.push(b);
+                // parameters and intermediates, then a final byte in `@`..=`~`
+                if (0x40..=0x7e).contains(&b) {
+                    self.finish_csi(output, chunks);
+                } else if self.seq.len() > MAX_CSI_LEN {
+                    output.append(&mut self.seq);
+                    self.state = ParserState::Ground;
+                }
+            }
+            ParserState::Osc => {
+                if b == BEL {
+                    self.finish_osc(1, b, output, chunks);
+                } else if b == ESC {
+                    self.state = ParserState::OscEscape;
+                    self.seq.push(b);
+                } else {
+                    self.seq.push(b);
+                    if self.seq.len() > MAX_OSC_LEN {
+                        output.append(&mut self.seq);
+                        self.state = ParserState::Ground;
+                    }
+                }
+            }
+            ParserState::OscEscape => {
+                if b == b'\\' {
+                    self.finish_osc(2, b, output, chunks);
+                } else {
+                    // unterminated OSC sequence, pass it through
+                    output.append(&mut self.seq);
+                    self.state = ParserState::Ground;
+                    self.step(b, output, chunks);
+                }
+            }
+        }
+    }
+
+    /// Complete the CSI sequence, whose final byte is the last in `seq`
+    fn finish_csi(&mut self, output: &mut Vec<u8>, chunks: &mut Vec<PtyChunk>) {
+        self.state = ParserState::Ground;
+        // strip the `ESC [` prefix and the final byte
+        let params = &self.seq[2..self.seq.len() - 1];
+        let active = match self.seq.last() {
+            Some(b'h') => true,
+            Some(b'l') => false,
+            _ => {
+                output.append(&mut self.seq);
+                return;
+            }
+        };
+        if !ALTERNATE_SCREEN_MODES
+            .iter()
+            .any(|mode| mode.as_bytes() == params)
+        {
+            output.append(&mut self.seq);
+            return;
+        }
+        if !output.is_empty() {
+            chunks.push(PtyChunk::Output(std::mem::take(output)));
+        }
+        chunks.push(PtyChunk::Event(ShellEvent::AlternateScreen { active }));
+        self.seq.clear();
+    }
+
+    /// Complete the OSC sequence with the final terminator byte `b`.
+    /// `terminator_len` is the length of the terminator (`BEL` or `ESC \`).
+    fn finish_osc(
+        &mut self,
+        terminator_len: usize,
+        b: u8,
+        output: &mut Vec<u8>,
+        chunks: &mut Vec<PtyChunk>,
+    ) {
+        self.seq.push(b);
+        self.state = ParserState::Ground;
+        // strip the `ESC ]` prefix and the terminator
+        let payload = &self.seq[2..self.seq.len() - terminator_len];
+        match ShellEvent::parse(payload) {
+            Some(event) => {
+                if !output.is_empty() {
+                    chunks.push(PtyChunk::Output(std::mem::take(output)));
+                }
+                chunks.push(PtyChunk::Event(event));
+                self.seq.clear();
+            }
+            None => output.append(&mut self.seq),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(bytes: &[u8]) -> PtyChunk {
+        PtyChunk::Output(bytes.to_vec())
+    }
+
+    fn event(event: ShellEvent) -> PtyChunk {
+        PtyChunk::Event(event)
+    }
+
+    #[test]
+    fn quotes_the_fish_init_path() {
+        assert_eq!(
+            fish_quote(OsStr::new("/tmp/wtg-ab/wtg.fish")),
+            "'/tmp/wtg-ab/wtg.fish'"
+        );
+        assert_eq!(
+            fish_quote(OsStr::new("/tmp/my dir/it's\\wtg.fish")),
+            "'/tmp/my dir/it\\'s\\\\wtg.fish'"
+        );
+        let dir = SessionDir::create().unwrap();
+        let integration = ShellIntegration::prepare("/usr/bin/fish", &dir)
+            .unwrap()
+            .unwrap();
+        let source = format!("source '{}'", dir.path().join("wtg.fish").display());
+        assert_eq!(
+            integration.argv,
+            [
+                to_cstring("/usr/bin/fish").unwrap(),
+                to_cstring("--init-command").unwrap(),
+                to_cstring(source).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_output_on_markers() {
+        let mut parser = OscParser::default();
+        let chunks = parser.feed(
+            b"\x1b]133;A\x07$ \x1b]133;C;cmdline_url=ls%20-l\x07total 0\r\n\x1b]133;D;2\x1b\\done",
+        );
+        assert_eq!(
+            chunks,
+            [
+                event(ShellEvent::PromptStart),
+                output(b"$ "),
+                event(ShellEvent::CommandStart {
+                    command: Some("ls -l".to_string())
+                }),
+                output(b"total 0\r\n"),
+                event(ShellEvent::CommandEnd { exit_code: Some(2) }),
+                output(b"done"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_markers_without_parameters() {
+        let mut parser = OscParser::default();
+        assert_eq!(
+            parser.feed(b"\x1b]133;C\x07\x1b]133;D\x07"),
+            [
+                event(ShellEvent::CommandStart { command: None }),
+                event(ShellEvent::CommandEnd { exit_code: None }),
+            ]
+        );
+    }
+
+    #[test]
+    fn holds_markers_split_across_reads() {
+        let mut parser = OscParser::default();
+        assert_eq!(parser.feed(b"out\x1b]13"), [output(b"out")]);
+        assert_eq!(parser.feed(b"3;D;"), []);
+        assert_eq!(
+            parser.feed(b"1\x1b"),
+            [],
+            "the ESC may start the ESC \\ terminator"
+        );
+        assert_eq!(
+            parser.feed(b"\\next"),
+            [
+                event(ShellEvent::CommandEnd { exit_code: Some(1) }),
+                output(b"next"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_working_directory() {
+        let mut parser = OscParser::default();
+        assert_eq!(
+            parser.feed(b"\x1b]7;file://host/home/me/my%20dir\x07"),
+            [event(ShellEvent::WorkingDirectory {
+                path: "/home/me/my dir".to_string()
+            })]
+        );
+    }
+
+    #[test]
+    fn parses_control_sequences() {
+        let cases = [
+            ("pause", ShellEvent::Pause),
+            ("resume", ShellEvent::Resume),
+            ("wtg", ShellEvent::WtgCommand),
+            ("mark", ShellEvent::Mark { exit_code: None }),
+            ("mark;2", ShellEvent::Mark { exit_code: Some(2) }),
+        ];
+        for (command, expected) in cases {
+            let mut parser = OscParser::default();
+            assert_eq!(
+                parser.feed(control_sequence(command).as_bytes()),
+                [event(expected)]
+            );
+        }
+        // unknown commands are passed through
+        let unknown = control_sequence("mark;x");
+        let mut parser = OscParser::default();
+        assert_eq!(
+            parser.feed(unknown.as_bytes()),
+            [output(unknown.as_bytes())]
+        );
+    }
+
+    #[test]
+    fn parses_alternate_screen_switches() {
+        let mut parser = OscParser::default();
+        assert_eq!(
+            parser.feed(b"\x1b[?1049hvim\x1b[?1049l\x1b[?47h"),
+            [
+                event(ShellEvent::AlternateScreen { active: true }),
+                output(b"vim"),
+                event(ShellEvent::AlternateScreen { active: false }),
+                event(ShellEvent::AlternateScreen { active: true }),
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_other_sequences_through() {
+        let bytes: &[u8] = b"\x1b[1;31mred\x1b[0m \x1b[?25l\x1b]0;title\x07\x1b(B\x1b]133;Z\x07";
+        let mut parser = OscParser::default();
+        assert_eq!(parser.feed(bytes), [output(bytes)]);
+        // an unterminated OSC sequence is output once it is too long to be a marker
+        let mut parser = OscParser::default();
+        let mut bytes = b"\x1b]133;".to_vec();
+        bytes.resize(MAX_OSC_LEN + 1, b'x');
+        assert_eq!(parser.feed(&bytes), [output(&bytes)]);
+    }
+
+    #[test]
+    fn finds_command_end_position() {
+        let bytes = b"out\x1b]133;D;0\x07\x1b]133;A\x07$ ";
+        assert_eq!(command_end_position(bytes), Some(13));
+        assert_eq!(command_end_position(b"a\x1b]133;D\x1b\\b"), Some(10));
+        // incomplete
+        assert_eq!(command_end_position(b"out\x1b]133;D;0"), None);
+    }
+}
@@ -0,0 +1,56 @@
+# wtg shell integration for bash. Emits OSC 133 markers so the wtg session
+# knows exactly where each prompt and command starts and ends.
+# `--rcfile` replaces both the system and the user's bashrc, so source them as
+# bash would (e.g. Debian and Ubuntu load completion from `/etc/bash.bashrc`)
+if [ -f /etc/bash.bashrc ]; then
+    . /etc/bash.bashrc
+fi
+if [ -f ~/.bashrc ]; then
+    . ~/.bashrc
+fi
+
+# `PS0` (needed to mark the start of a command) requires bash 4.4+, otherwise
+# the session falls back to detecting commands from the Enter key
+if [ "${BASH_VERSINFO[0]}" -gt 4 ] || { [ "${BASH_VERSINFO[0]}" -eq 4 ] && [ "${BASH_VERSINFO[1]}" -ge 4 ]; }; then
+    __wtg_urlencode() {
+        local LC_ALL=C s="$1" out="" c i
+        for (( i = 0; i < ${#s}; i++ )); do
+            c="${s:i:1}"
+            case "$c" in
+                [a-zA-Z0-9.~_/=-]) out+="$c" ;;
+                *) printf -v c '%%%02X' "'$c"; out+="$c" ;;
+            esac
+        done
+        printf '%s' "$out"
+    }
+
+    __wtg_precmd() {
+        local status=$?
+        # the session ignores the end marker if no command was run (e.g. an empty line)
+        printf '\033]133;D;%s\007' "$status"
+        printf '\033]7;file://%s%s\007' "$HOSTNAME" "$(__wtg_urlencode "$PWD")"
+        printf '\033]133;A\007'
+        __wtg_last_history="$(HISTTIMEFORMAT= builtin history 1)"
+        return $status
+    }
+
+    # expanded in `PS0`, i.e. in a subshell after the command is read
+    __wtg_preexec() {
+        local entry
+        entry="$(HISTTIMEFORMAT= builtin history 1)"
+        # the command line is only known if it was added to the history
+        if [ "$entry" != "$__wtg_last_history" ] && [[ "$entry" =~ ^[[:space:]]*[0-9]+[*]?[[:space:]]+(.*)$ ]]; then
+            printf '\033]133;C;cmdline_url=%s\007' "$(__wtg_urlencode "${BASH_REMATCH[1]}")"
+        else
+            printf '\033]133;C\007'
+        fi
+    }
+
+    # run first so `$?` is still the exit status of the command
+    if [[ "$(declare -p PROMPT_COMMAND 2>/dev/null)" == "declare -a"* ]]; then
+        PROMPT_COMMAND=(__wtg_precmd "${PROMPT_COMMAND[@]}")
+    else
+        PROMPT_COMMAND="__wtg_precmd${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+    fi
+    PS0='$(__wtg_preexec)'"${PS0}"
+fi
@@ -0,0 +1,14 @@
+# wtg shell integration for fish. Emits OSC 133 markers so the wtg session
+# knows exactly where each prompt and command starts and ends.
+function __wtg_prompt --on-event fish_prompt
+    printf '\e]7;file://%s%s\a' $hostname (string escape --style=url -- $PWD)
+    printf '\e]133;A\a'
+end
+
+function __wtg_preexec --on-event fish_preexec
+    printf '\e]133;C;cmdline_url=%s\a' (string escape --style=url -- $argv[1])
+end
+
+function __wtg_postexec --on-event fish_postexec
+    printf '\e]133;D;%s\a' $status
+end